@@ -1,22 +1,135 @@
 use shared::runtime::Runtime;
 
-use shared::assembler::Assembler;
-use shared::error::AssemblerError;
-use shared::lexer::LexerResult;
-use shared::lexer::LineStructure;
+use shared::assembler::{assemble_object, assemble_with_options, AssemblerOptions};
+use shared::disassembler::disassemble;
+use shared::formatter;
+use shared::json::JsonDiagnostic;
+use shared::lexer::{is_generated_label, is_pool_label, LabelLookup, SourceFile, Sources};
+use shared::listing::Listing;
+use shared::object::{link, ObjectFile};
+use shared::source_map::SourceMap;
 pub use shared::Mailbox;
-use shared::{assembler, lexer, StdRuntime};
+use shared::StdRuntime;
 use std::collections::HashMap;
-use std::io::{stdin, stdout, BufRead, BufReader, Write};
+use std::io::{stdin, stdout, Write};
 use std::{env, fs, process};
 
+/// Returns the argument following `flag`, e.g. `--max-errors 5` gives `5`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+}
+
+/// Collects every `-D NAME=value` argument. `-D NAME` alone sets `NAME` to 1.
+fn defines(args: &[String]) -> HashMap<String, String> {
+    args.windows(2)
+        .filter(|pair| pair[0] == "-D")
+        .map(|pair| match pair[1].split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => (pair[1].clone(), String::from("1")),
+        })
+        .collect()
+}
+
+/// Source files referenced by a [`SourceMap`], used to quote source lines while debugging.
+struct SourceView {
+    source_map: SourceMap,
+    files: Vec<Vec<String>>,
+}
+impl SourceView {
+    /// Files missing from `sources`, which is empty for a program loaded from a `.bin`, are
+    /// read from disk.
+    fn new(source_map: SourceMap, sources: &[&SourceFile]) -> Self {
+        let files = source_map
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| match sources.get(index) {
+                Some(source) => source.text.lines().map(String::from).collect(),
+                None => fs::read_to_string(file)
+                    .map(|source| source.lines().map(String::from).collect())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        Self { source_map, files }
+    }
+    fn describe(&self, address: u16) -> Option<String> {
+        let location = self.source_map.get(address)?;
+        let literal = self.files[location.file as usize]
+            .get(location.line as usize)
+            .map_or("", |line| line.trim());
+        Some(format!(
+            "{}:{} | {}",
+            self.source_map.file_name(location),
+            location.line + 1,
+            literal
+        ))
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     if let Some(command) = args.get(1) {
+        let source: String;
+        let source_files = Sources::new();
         let mailbox: Mailbox;
-        let label_lookup: HashMap<String, u16> = HashMap::new();
+        let mut label_lookup = LabelLookup::new();
+        let mut source_map = None;
+        let mut listing = None;
+        let mut sources = Vec::new();
         if let Some(filename) = args.get(2) {
+            if command == "fmt" {
+                let source = fs::read_to_string(filename).expect("Failed to open file");
+                let formatted = formatter::format(&source);
+                if formatted == source {
+                    return;
+                }
+                if args.iter().any(|arg| arg == "--check") {
+                    println!("{} is not formatted", filename);
+                    process::exit(1);
+                }
+                fs::write(filename, formatted).expect("Failed to write formatted file");
+                return;
+            }
+            if command == "link" {
+                let json = args.iter().any(|arg| arg == "--message-format=json");
+                let objects: Vec<ObjectFile> = args[2..]
+                    .iter()
+                    .filter(|arg| !arg.starts_with("--"))
+                    .map(|filename| {
+                        let mut file = fs::File::open(filename).expect("Failed to open file");
+                        ObjectFile::read_from_file(&mut file).unwrap_or_else(|e| {
+                            if json {
+                                println!("{}", JsonDiagnostic::object_error(&e, filename));
+                            } else {
+                                println!("{}: {}", filename, e);
+                            }
+                            process::exit(1);
+                        })
+                    })
+                    .collect();
+                match link(&objects) {
+                    Ok(program) => {
+                        let mut target_file = fs::File::create("program.bin").expect("Failed to create file");
+                        program.mailbox.export_to_file(&mut target_file).expect("Failed to write linked file");
+                        let mut map_file = fs::File::create("program.map").expect("Failed to create file");
+                        program.source_map.export_to_file(&mut map_file).expect("Failed to write source map");
+                    }
+                    Err(errors) => {
+                        for error in &errors {
+                            if json {
+                                println!("{}", JsonDiagnostic::from(error));
+                            } else {
+                                println!("{}", error);
+                            }
+                        }
+                        process::exit(1);
+                    }
+                }
+                return;
+            }
             if filename.ends_with(".bin") {
                 let mut file = fs::OpenOptions::new()
                     .read(true)
@@ -24,54 +137,89 @@ fn main() {
                     .expect("Failed to open file");
                 let mailbox_from_bin = Mailbox::read_from_file(&mut file);
                 mailbox = mailbox_from_bin.expect("Failed to read mailbox");
+                let map_filename = filename.trim_end_matches(".bin").to_owned() + ".map";
+                if let Ok(mut map_file) = fs::File::open(map_filename) {
+                    source_map = SourceMap::read_from_file(&mut map_file).ok();
+                }
             } else {
-                let mut new_mailbox = Mailbox::new();
-                let label_lookup;
-                let mut lexer_result: LexerResult = [const { None }; 100];
-                let file = fs::OpenOptions::new()
-                    .read(true)
-                    .open(filename)
-                    .expect("Failed to open file");
-                {
-                    let lines = BufReader::new(&file).lines();
-                    let mut lexer = lexer::Lexer::new(lines);
-                    let result = (&mut lexer)
-                        .collect::<Result<Vec<Option<LineStructure>>, AssemblerError>>();
-                    label_lookup = lexer.get_label_lookup().clone();
-                    match result {
-                        Ok(result) => {
-                            for (i, line) in result.into_iter().enumerate() {
-                                lexer_result[i] = line;
+                source = fs::read_to_string(filename).expect("Failed to open file");
+                let mut options = AssemblerOptions {
+                    file_name: Some(filename.clone()),
+                    defines: defines(&args),
+                    ..Default::default()
+                };
+                if let Some(max_errors) = flag_value(&args, "--max-errors") {
+                    match max_errors.parse() {
+                        Ok(max_errors) if max_errors > 0 => options.max_errors = max_errors,
+                        _ => {
+                            println!("--max-errors must be a positive integer");
+                            process::exit(1);
+                        }
+                    }
+                }
+                let json = args.iter().any(|arg| arg == "--message-format=json");
+                if let Some(object_filename) = flag_value(&args, "--object") {
+                    match assemble_object(&source_files, &source, &options) {
+                        Ok((object, warnings)) => {
+                            for warning in &warnings {
+                                if json {
+                                    println!("{}", JsonDiagnostic::from(warning));
+                                } else {
+                                    println!("{}", warning);
+                                    println!();
+                                }
                             }
+                            let mut object_file = fs::File::create(object_filename).expect("Failed to create file");
+                            object.export_to_file(&mut object_file).expect("Failed to write object file");
                         }
-                        Err(err) => {
-                            println!("{}", err);
+                        Err(diagnostics) => {
+                            if json {
+                                for error in &diagnostics.errors {
+                                    println!("{}", JsonDiagnostic::from(error));
+                                }
+                            } else {
+                                println!("{}", diagnostics);
+                            }
                             process::exit(1);
                         }
                     }
+                    return;
                 }
-                let file = fs::OpenOptions::new()
-                    .read(true)
-                    .open(filename)
-                    .expect("Failed to open file");
-                let source = BufReader::new(&file).lines();
-                let mut assembler = Assembler::new(source, label_lookup, lexer_result);
-                loop {
-                    match assembler.parse_line() {
-                        assembler::State::Ok(opcode) => {
-                            new_mailbox[assembler.current_line() - 1] =
-                                opcode.to_numeric_representation();
+                match assemble_with_options(&source_files, &source, &options) {
+                    Ok(program) => {
+                        for warning in &program.warnings {
+                            if json {
+                                println!("{}", JsonDiagnostic::from(warning));
+                            } else {
+                                println!("{}", warning);
+                                println!();
+                            }
                         }
-                        assembler::State::Err(err) => {
-                            println!("{}", err);
-                            process::exit(1);
+                        if !json {
+                            println!("Program size: {}", program.size());
                         }
-                        assembler::State::Done => break,
+                        if flag_value(&args, "--listing").is_some() {
+                            listing = Some(Listing::new(&program).to_string());
+                        }
+                        mailbox = program.mailbox;
+                        label_lookup = program.labels;
+                        source_map = Some(program.source_map);
+                        sources = program.sources;
+                    }
+                    Err(diagnostics) => {
+                        if json {
+                            for error in &diagnostics.errors {
+                                println!("{}", JsonDiagnostic::from(error));
+                            }
+                        } else {
+                            println!("{}", diagnostics);
+                        }
+                        process::exit(1);
                     }
                 }
-
-                mailbox = new_mailbox;
-                println!("{:?}", mailbox);
+                if !json {
+                    println!("{:?}", mailbox);
+                }
             }
             match command.as_str() {
                 "run" => {
@@ -89,10 +237,18 @@ fn main() {
                         .open(target_filename)
                         .expect("Failed to create file");
                     mailbox.export_to_file(&mut target_file).expect("Failed to write assembled file");
+                    if let Some(source_map) = source_map {
+                        let mut map_file = fs::File::create("program.map").expect("Failed to create file");
+                        source_map.export_to_file(&mut map_file).expect("Failed to write source map");
+                    }
+                    if let (Some(listing_filename), Some(listing)) = (flag_value(&args, "--listing"), listing) {
+                        fs::write(listing_filename, listing).expect("Failed to write listing");
+                    }
                 }
-
+                "disassemble" => print!("{}", disassemble(&mailbox)),
                 "debug" => {
-                    let label_info: HashMap<u16, String> = label_lookup.iter().map(|(k, v)| (*v, k.clone())).collect();
+                    let label_info: HashMap<u16, String> = label_lookup.iter().filter(|(k, _)| !is_generated_label(k) && !is_pool_label(k)).map(|(k, v)| (*v, k.clone())).collect();
+                    let source_view = source_map.map(|source_map| SourceView::new(source_map, &sources));
                     let mut runtime = StdRuntime::new(mailbox);
                     let mut breakpoints: Vec<u16> = vec![];
                     loop {
@@ -106,6 +262,9 @@ fn main() {
                                 let addr = runtime.common.program_counter;
                                 if breakpoints.contains(&addr) {
                                     println!("(Breakpoint hit at address: {})", addr);
+                                    if let Some(source) = source_view.as_ref().and_then(|view| view.describe(addr)) {
+                                        println!("    at {}", source);
+                                    }
                                 }
                             }
                             ["step"] => {
@@ -127,6 +286,9 @@ fn main() {
                                 } else if let (None, literal) = current {
                                     println!("{}{}", line_label, literal);
                                 }
+                                if let Some(source) = source_view.as_ref().and_then(|view| view.describe(line)) {
+                                    println!("    at {}", source);
+                                }
                                 runtime.evaluate_current();
                             }
                             ["mailbox"] => println!("{:?}", runtime.common.mailbox),
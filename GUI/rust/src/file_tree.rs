@@ -2,13 +2,11 @@ use crate::watcher::{WatcherState, WatcherStruct};
 use godot::classes::{ITree, TextEdit, Tree, TreeItem};
 use godot::meta::AsObjectArg;
 use godot::prelude::*;
-use std::{fs, process};
-use std::io::{BufRead, BufReader, Cursor, Lines};
+use std::fs;
 use std::path::Path;
-use shared::{lexer, Mailbox};
-use shared::lexer::{Lexer, LexerResult, LineStructure};
-use shared::assembler::{Assembler, State};
-use shared::error::AssemblerError;
+use shared::Mailbox;
+use shared::assembler::assemble;
+use shared::lexer::Sources;
 use shared::runtime::{Runtime, RuntimeCommon, RuntimeState};
 
 #[derive(GodotClass)]
@@ -131,42 +129,22 @@ impl FileTree {
     }
     #[func]
     fn run_button(&mut self) {
-        let mut mailbox=Mailbox::new();
-        let mut code_edit: Gd<TextEdit> = self.base_mut().get_node_as("../CodeEdit");
+        let code_edit: Gd<TextEdit> = self.base_mut().get_node_as("../CodeEdit");
         let code = code_edit.get_text();
         godot_print!("Running code: {}", code);
-        let mut lexer=Lexer::new(BufReader::new(Cursor::new(code.to_string())).lines());
-        let result = (&mut lexer)
-            .collect::<Result<Vec<Option<LineStructure>>, AssemblerError>>();
-        let label_lookup = lexer.get_label_lookup().clone();
-        let mut lexer_result: LexerResult = [const { None }; 100];
-        match result {
-            Ok(result) => {
-                for (i, line) in result.into_iter().enumerate() {
-                    lexer_result[i] = line;
+        let code = code.to_string();
+        let sources = Sources::new();
+        match assemble(&sources, &code) {
+            Ok(program) => {
+                for warning in &program.warnings {
+                    godot_warn!("{}", warning);
                 }
+                godot_print!("Program size: {}", program.size());
+                let mut runtime = GUIRuntime::new(program.mailbox);
+                runtime.start();
             }
-            Err(err) => {
-                println!("{}", err);
-                process::exit(1);
-            }
-        }
-        let mut assembler = Assembler::new(Cursor::new(code.to_string()).lines(), label_lookup, lexer_result);
-        loop {
-            match assembler.parse_line() {
-                State::Ok(opcode) => {
-                    mailbox[assembler.current_line() - 1] =
-                        opcode.to_numeric_representation();
-                }
-                State::Err(err) => {
-                    println!("{}", err);
-                    process::exit(1);
-                }
-                State::Done => break,
-            }
+            Err(diagnostics) => godot_error!("{}", diagnostics),
         }
-        let mut runtime = GUIRuntime::new(mailbox);
-        runtime.start();
     }
 }
 pub struct GUIRuntime{
@@ -1,4 +1,6 @@
 mod watcher;
+// The glue #[godot_api] generates next to FileTree's methods returns a large CallError.
+#[allow(clippy::result_large_err)]
 mod file_tree;
 
 use godot::prelude::*;
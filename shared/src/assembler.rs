@@ -1,12 +1,23 @@
 use crate::error::AssemblerError::{
-    InstructionExpected, InstructionExpectedAddress, InstructionExpectedGotLabels, UnsetLabel,
+    ConstantPoolTooLarge, InstructionExpected, InstructionExpectedAddress,
+    InstructionExpectedGotLabels, NotRelocatable, OperandOutOfRange, OverlappingAddress,
+    ProgramTooLarge,
 };
-use crate::error::{AssemblerError, ErrorInfo};
-use crate::lexer::{LexerResult, LineStructure, RightField};
-use crate::OpCode;
-use std::collections::HashMap;
-use std::io::{BufRead, Lines};
+use crate::error::{AssemblerError, AssemblerWarning, Diagnostics, ErrorInfo};
+use crate::lexer::{
+    is_pool_label, LabelLookup, Lexer, LexerResult, LexerState, LinePart, LineStructure,
+    RightField, SourceFile, Sources,
+};
+use crate::object::{ObjectFile, Relocation};
+use crate::source_map::{SourceLocation, SourceMap};
+use crate::warnings::find_warnings;
+use crate::{Mailbox, MemonicType, OpCode};
+use std::boxed::Box;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
 use std::string::{String, ToString};
+use std::vec::Vec;
 
 pub enum State<T, E> {
     Ok(T),
@@ -14,139 +25,165 @@ pub enum State<T, E> {
     Done,
 }
 
-pub struct Assembler<T: BufRead> {
-    line_structure: LexerResult,
+/// How many times the value of a label matching `is_symbol` is added into `field`, 1 for
+/// `TABLE+2` but 0 for `END-START`.
+fn coefficient(field: &RightField, is_symbol: &impl Fn(&str) -> bool) -> i32 {
+    match field {
+        RightField::Label(label) => is_symbol(label) as i32,
+        RightField::Expression(expression) => {
+            let Ok(coefficient) = expression.evaluate(&mut |operand| {
+                Ok::<_, Infallible>(coefficient(&operand.value, is_symbol))
+            });
+            coefficient
+        }
+        RightField::Literal(_) | RightField::Address(_) | RightField::Text(_) => 0,
+        RightField::Constant(_) => unreachable!("constants are placed in the pool by the lexer"),
+        RightField::List(_) => unreachable!("lists are split into cells by the lexer"),
+    }
+}
+
+/// Names whose address is only known once objects are linked.
+struct Relocatable {
+    labels: HashSet<String>,
+    imports: HashSet<String>,
+}
+
+pub struct Assembler<'a> {
+    line_structure: LexerResult<'a>,
     table_lookup: HashMap<String, u16>,
-    current_line: u16,
-    source: Lines<T>,
-}
-impl<'a, T: BufRead> Assembler<T> {
-    pub fn new(
-        source: Lines<T>,
-        table_lookup: HashMap<String, u16>,
-        line_structure: LexerResult,
-    ) -> Self {
+    /// Address of the line assembled last.
+    current_address: u16,
+    next_address: usize,
+    relocatable: Option<Relocatable>,
+    relocations: Vec<Relocation>,
+}
+impl<'a> Assembler<'a> {
+    pub fn new(table_lookup: HashMap<String, u16>, line_structure: LexerResult<'a>) -> Self {
         Self {
             line_structure,
             table_lookup,
-            current_line: 0,
-            source,
+            current_address: 0,
+            next_address: 0,
+            relocatable: None,
+            relocations: Vec::new(),
         }
     }
-    pub fn parse_line(&mut self) -> State<OpCode, AssemblerError> {
-        let current_line = &self.line_structure[self.current_line as usize];
-        self.current_line += 1;
-        if let Some(line) = current_line {
-            match line {
+    /// Assembles an object instead of a program. `labels` are taken as relative to the start
+    /// of the object and `imports` as 0, and every operand using them is left at 0 with a
+    /// [`Relocation`] for the linker.
+    pub fn relocatable(mut self, labels: HashSet<String>, imports: HashSet<String>) -> Self {
+        self.relocatable = Some(Relocatable { labels, imports });
+        self
+    }
+    pub fn parse_line(&mut self) -> State<OpCode, AssemblerError<'a>> {
+        // Addresses skipped over by `ORG` have no line.
+        let next_line = self
+            .line_structure
+            .iter()
+            .enumerate()
+            .skip(self.next_address)
+            .find_map(|(address, line)| line.as_ref().map(|line| (address, line)));
+        if let Some((address, current)) = next_line {
+            self.current_address = address as u16;
+            self.next_address = address + 1;
+            match current {
                 LineStructure {
                     left: _left,
                     instruction: Some(instruction),
                     right: Some(right),
-                    line,
+                    ..
                 } => {
-                    let address: u16;
-                    match &right.value {
-                        RightField::Literal(value) => {
-                            address = *value;
-                        }
-                        RightField::Label(label) => {
-                            if let Some(addr) = self.table_lookup.get(label) {
-                                address = *addr;
-                            } else {
-                                return State::Err(UnsetLabel(
-                                    ErrorInfo::new(right.start, right.end, *line, &mut self.source),
-                                    label.clone(),
-                                ))
-                            }
-                        }
-                        RightField::Address(addr) => {
-                            address = *addr;
+                    let address = match self.resolve(right, current) {
+                        Ok(value) => value,
+                        Err(err) => return State::Err(err),
+                    };
+                    let max = if instruction.value == MemonicType::DAT {
+                        999
+                    } else {
+                        99
+                    };
+                    let address = match self.relocation(right, current, address, max as u16) {
+                        Ok(Some(relocation)) => {
+                            // The linker checks the range once the address is known.
+                            self.relocations.push(relocation);
+                            0
                         }
+                        Ok(None) => address,
+                        Err(err) => return State::Err(err),
+                    };
+                    if !(0..=max).contains(&address) {
+                        return State::Err(OperandOutOfRange(
+                            ErrorInfo::new(right.start, right.end, current),
+                            address,
+                            max as u16,
+                        ));
                     }
+                    let address = address as u16;
                     if let Ok(instruction) =
                         OpCode::try_from_mnemonic_type(instruction.value, Some(address))
                     {
                         State::Ok(instruction)
                     } else {
                         State::Err(InstructionExpectedAddress(
-                            ErrorInfo::new(right.start, right.end, *line, &mut self.source),
+                            ErrorInfo::new(right.start, right.end, current),
                             instruction.value,
                         ))
                     }
                 }
-                #[allow(unused_variables)]
                 LineStructure {
-                    left,
+                    left: _left,
                     instruction: Some(instruction),
                     right: None,
-                    line,
+                    ..
                 } => {
-                    if let Some(left) = left {
-                        self.table_lookup
-                            .insert(left.value.to_string(), self.current_line);
-                    }
                     if let Ok(instruction) = OpCode::try_from_mnemonic_type(instruction.value, None)
                     {
                         State::Ok(instruction)
                     } else {
                         State::Err(InstructionExpectedAddress(
-                            ErrorInfo::new(
-                                instruction.start,
-                                instruction.end,
-                                *line,
-                                &mut self.source,
-                            ),
+                            ErrorInfo::new(instruction.start, instruction.end, current),
                             instruction.value,
                         ))
                     }
                 }
-                #[allow(unused_variables)]
                 LineStructure {
                     left: Some(left),
                     instruction: None,
                     right: None,
-                    line,
+                    ..
                 } => State::Err(InstructionExpectedGotLabels(ErrorInfo::new(
-                    left.start,
-                    left.end,
-                    *line,
-                    &mut self.source,
+                    left.start, left.end, current,
                 ))),
-                #[allow(unused_variables)]
                 LineStructure {
                     left: None,
                     instruction: None,
                     right: Some(right),
-                    line,
+                    ..
                 } => State::Err(InstructionExpectedGotLabels(ErrorInfo::new(
                     right.start,
                     right.end,
-                    *line,
-                    &mut self.source,
+                    current,
                 ))),
-                #[allow(unused_variables)]
                 LineStructure {
                     left: Some(left),
                     instruction: None,
                     right: Some(right),
-                    line,
+                    ..
                 } => State::Err(InstructionExpected(ErrorInfo::new(
-                    left.start,
-                    right.end,
-                    *line,
-                    &mut self.source,
+                    left.start, right.end, current,
                 ))),
-                #[allow(unused_variables)]
                 LineStructure {
                     left: None,
                     instruction: None,
                     right: None,
                     line,
+                    source,
+                    ..
                 } => {
                     panic!(
                         "Unexpected empty line structure at line {}, literal: {}",
                         line,
-                        self.source.nth(*line as usize).unwrap().unwrap()
+                        source.literal()
                     )
                 }
             }
@@ -154,7 +191,753 @@ impl<'a, T: BufRead> Assembler<T> {
             State::Done
         }
     }
-    pub fn current_line(&self) -> u16 {
-        self.current_line
+    pub fn current_address(&self) -> u16 {
+        self.current_address
+    }
+    pub fn lines(&self) -> &LexerResult<'a> {
+        &self.line_structure
+    }
+    /// Operands left for the linker, see [`Assembler::relocatable`].
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+    /// The relocation of `operand`, which resolved to `value`, if it depends on where the
+    /// object is placed. That is only the case for one label plus or minus a constant.
+    fn relocation(
+        &self,
+        operand: &LinePart<RightField<'a>>,
+        current: &LineStructure<'a>,
+        value: i32,
+        max: u16,
+    ) -> Result<Option<Relocation>, AssemblerError<'a>> {
+        let Some(relocatable) = &self.relocatable else {
+            return Ok(None);
+        };
+        let base = coefficient(&operand.value, &|label| relocatable.labels.contains(label));
+        let mut imports = operand.value.labels();
+        imports.retain(|label| relocatable.imports.contains(*label));
+        imports.sort();
+        imports.dedup();
+        let imported: Vec<(&str, i32)> = imports
+            .into_iter()
+            .map(|name| (name, coefficient(&operand.value, &|label| label == name)))
+            .filter(|(_, coefficient)| *coefficient != 0)
+            .collect();
+        let symbol = match (base, imported.as_slice()) {
+            (0, []) => return Ok(None),
+            (1, []) => None,
+            (0, [(name, 1)]) => Some(name.to_string()),
+            _ => {
+                return Err(NotRelocatable(ErrorInfo::new(
+                    operand.start,
+                    operand.end,
+                    current,
+                )))
+            }
+        };
+        Ok(Some(Relocation {
+            address: self.current_address,
+            max,
+            addend: value,
+            symbol,
+        }))
+    }
+    fn resolve(
+        &self,
+        operand: &LinePart<RightField<'a>>,
+        current: &LineStructure<'a>,
+    ) -> Result<i32, AssemblerError<'a>> {
+        match &operand.value {
+            RightField::Literal(value) | RightField::Address(value) => Ok(*value as i32),
+            RightField::Label(label) => match self.table_lookup.get(label.as_ref()) {
+                Some(address) => Ok(*address as i32),
+                None => Err(AssemblerError::unset_label(
+                    ErrorInfo::new(operand.start, operand.end, current),
+                    label,
+                    self.table_lookup.keys().map(String::as_str),
+                )),
+            },
+            RightField::Expression(expression) => {
+                expression.evaluate(&mut |operand| self.resolve(operand, current))
+            }
+            RightField::Text(_) => unreachable!("strings are split into cells by the lexer"),
+            RightField::Constant(_) => {
+                unreachable!("constants are placed in the pool by the lexer")
+            }
+            RightField::List(_) => unreachable!("lists are split into cells by the lexer"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AssembledProgram<'a> {
+    pub mailbox: Mailbox,
+    pub labels: LabelLookup,
+    /// Names defined with `EQU`.
+    pub constants: LabelLookup,
+    pub source_map: SourceMap,
+    /// The instruction assembled into every filled address.
+    pub opcodes: [Option<OpCode>; 100],
+    /// Every file in [`SourceMap::files`], in the same order.
+    pub sources: Vec<&'a SourceFile<'a>>,
+    /// Suspicious but valid code, see [`find_warnings`].
+    pub warnings: Vec<AssemblerWarning<'a>>,
+}
+
+/// How the 100 mailbox cells of a program are used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgramSize {
+    pub code: usize,
+    pub data: usize,
+    pub free: usize,
+}
+impl Display for ProgramSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} code cells, {} data cells, {} free cells",
+            self.code, self.data, self.free
+        )
+    }
+}
+
+impl AssembledProgram<'_> {
+    pub fn size(&self) -> ProgramSize {
+        let data = self
+            .opcodes
+            .iter()
+            .filter(|opcode| matches!(opcode, Some(OpCode::DAT(_))))
+            .count();
+        let used = self.opcodes.iter().flatten().count();
+        ProgramSize {
+            code: used - data,
+            data,
+            free: self.opcodes.len() - used,
+        }
+    }
+}
+
+pub struct AssemblerOptions {
+    /// Maximum number of errors kept in the returned [`Diagnostics`].
+    pub max_errors: usize,
+    /// Name recorded for the source in the [`SourceMap`]. `INCLUDE` paths are resolved
+    /// relative to this file.
+    pub file_name: Option<String>,
+    /// Values for names tested by `IF`, such as `TARGET` set to `microbit`.
+    pub defines: HashMap<String, String>,
+}
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        Self {
+            max_errors: 20,
+            file_name: None,
+            defines: HashMap::new(),
+        }
+    }
+}
+
+/// Runs the lexer and the assembler over `source` and returns the assembled mailbox,
+/// together with the label table and the source location of every filled address. The
+/// program and its errors borrow from `sources`, which keeps `source` and the files it
+/// includes.
+pub fn assemble<'a>(
+    sources: &'a Sources<'a>,
+    source: &'a str,
+) -> Result<AssembledProgram<'a>, Diagnostics<'a>> {
+    assemble_with_options(sources, source, &AssemblerOptions::default())
+}
+
+/// Same as [`assemble`], but errors are collected according to `options`.
+/// Both the lexer and the assembler carry on with the next line after an error,
+/// so every broken line of the program is reported in one go.
+pub fn assemble_with_options<'a>(
+    sources: &'a Sources<'a>,
+    source: &'a str,
+    options: &AssemblerOptions,
+) -> Result<AssembledProgram<'a>, Diagnostics<'a>> {
+    assemble_program(sources, source, options, false).map(|(program, _)| program)
+}
+
+/// Assembles `source` into an object to be linked with others by [`crate::object::link`].
+/// Labels in the object start at address 0, and names declared with `IMPORT` may be used
+/// in operands as one label plus or minus a constant.
+pub fn assemble_object<'a>(
+    sources: &'a Sources<'a>,
+    source: &'a str,
+    options: &AssemblerOptions,
+) -> Result<(ObjectFile, Vec<AssemblerWarning<'a>>), Diagnostics<'a>> {
+    let (program, linkage) = assemble_program(sources, source, options, true)?;
+    let size = program
+        .opcodes
+        .iter()
+        .rposition(Option::is_some)
+        .map_or(0, |last| last + 1);
+    let object = ObjectFile {
+        name: options
+            .file_name
+            .clone()
+            .unwrap_or_else(|| String::from("<source>")),
+        cells: (0..size).map(|address| program.mailbox[address]).collect(),
+        exports: linkage.exports,
+        imports: linkage.imports,
+        relocations: linkage.relocations,
+        source_map: program.source_map,
+    };
+    Ok((object, program.warnings))
+}
+
+/// What an object holds besides its cells.
+#[derive(Default)]
+struct Linkage {
+    imports: Vec<String>,
+    exports: Vec<(String, u16)>,
+    relocations: Vec<Relocation>,
+}
+
+fn assemble_program<'a>(
+    sources: &'a Sources<'a>,
+    source: &'a str,
+    options: &AssemblerOptions,
+    relocatable: bool,
+) -> Result<(AssembledProgram<'a>, Linkage), Diagnostics<'a>> {
+    let mut errors = Vec::new();
+    let mut lexer = match &options.file_name {
+        Some(file_name) => Lexer::with_file(sources, source, file_name),
+        None => Lexer::new(sources, source),
+    }
+    .with_defines(options.defines.clone());
+    let mut lines = Vec::new();
+    for state in &mut lexer {
+        match state {
+            LexerState::Some(line) => lines.push(line),
+            LexerState::Err(err) => errors.push(err),
+            LexerState::Skip => {}
+        }
+    }
+    let labels = lexer.get_label_lookup().clone();
+    let constants = lexer.get_constants().clone();
+
+    let mut source_map = SourceMap::new();
+    let main_file = options.file_name.as_deref().unwrap_or("<source>");
+    for source in lexer.sources() {
+        source_map.add_file(source.name.as_deref().unwrap_or(main_file));
+    }
+    if let Some(first) = lines.iter().find(|line| line.address >= 100) {
+        // Every label past the end would also be out of range, so stop here.
+        let needed = lines.iter().map(|line| line.address as usize + 1).max();
+        let (start, end) = first.span();
+        let info = ErrorInfo::new(start, end, first);
+        let needed = needed.unwrap_or_default();
+        let pooled = labels
+            .iter()
+            .any(|(label, address)| is_pool_label(label) && *address == first.address);
+        errors.push(if pooled {
+            // The pool only runs past the end once every free cell holds a constant.
+            ConstantPoolTooLarge(info, lines.len())
+        } else {
+            ProgramTooLarge(info, needed)
+        });
+        return Err(Diagnostics::new(errors, options.max_errors));
+    }
+    let mut line_structure: LexerResult = [const { None }; 100];
+    for line in lines {
+        let file = source_map.add_file(line.file().unwrap_or(main_file));
+        // Macro expansions are mapped to the line that invoked the macro.
+        let location = match &line.invocation {
+            Some(invocation) => SourceLocation {
+                file,
+                line: invocation.line,
+                start: invocation.start,
+                end: invocation.end,
+            },
+            None => {
+                let (start, end) = line.span();
+                SourceLocation {
+                    file,
+                    line: line.line,
+                    start,
+                    end,
+                }
+            }
+        };
+        let address = line.address as usize;
+        if let Some(previous) = &line_structure[address] {
+            let (start, end) = line.span();
+            let (previous_start, previous_end) = previous.span();
+            errors.push(OverlappingAddress(
+                ErrorInfo::new(start, end, &line),
+                Box::new(ErrorInfo::new(previous_start, previous_end, previous)),
+                line.address,
+            ));
+            continue;
+        }
+        source_map.set(line.address, location);
+        line_structure[address] = Some(line);
+    }
+
+    let mut mailbox = Mailbox::new();
+    let mut opcodes = [const { None }; 100];
+    let mut symbols = labels.clone();
+    symbols.extend(constants.clone());
+    let mut linkage = Linkage::default();
+    if relocatable {
+        linkage.imports = lexer.get_imports().to_vec();
+        symbols.extend(linkage.imports.iter().map(|import| (import.clone(), 0)));
+        for (name, info) in lexer.get_exports() {
+            match labels.get(name) {
+                Some(_) if linkage.exports.iter().any(|(export, _)| export == name) => {}
+                Some(address) => linkage.exports.push((name.clone(), *address)),
+                None => errors.push(AssemblerError::unset_label(
+                    info.clone(),
+                    name,
+                    labels.keys().map(String::as_str),
+                )),
+            }
+        }
+    }
+    let mut assembler = Assembler::new(symbols, line_structure);
+    if relocatable {
+        assembler = assembler.relocatable(
+            labels.keys().cloned().collect(),
+            linkage.imports.iter().cloned().collect(),
+        );
+    }
+    loop {
+        match assembler.parse_line() {
+            State::Ok(opcode) => {
+                let address = assembler.current_address();
+                mailbox[address] = opcode.to_numeric_representation();
+                opcodes[address as usize] = Some(opcode);
+            }
+            State::Err(err) => errors.push(err),
+            State::Done => break,
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Diagnostics::new(errors, options.max_errors));
+    }
+    linkage.relocations = assembler.relocations().to_vec();
+    // Warnings about the target of an operand only make sense for targets in this object.
+    let mut targets = opcodes.clone();
+    for relocation in &linkage.relocations {
+        let address = relocation.address as usize;
+        let target = match relocation.symbol {
+            None => u16::try_from(relocation.addend)
+                .ok()
+                .filter(|target| *target <= 99),
+            Some(_) => None,
+        };
+        targets[address] = targets[address]
+            .as_ref()
+            .map(|opcode| opcode.with_address(target));
+    }
+    let warnings = find_warnings(
+        assembler.lines(),
+        &targets,
+        &labels,
+        lexer.get_definitions(),
+        lexer.get_references(),
+    );
+    let program = AssembledProgram {
+        mailbox,
+        labels,
+        constants,
+        source_map,
+        opcodes,
+        sources: lexer.sources().to_vec(),
+        warnings,
+    };
+    Ok((program, linkage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::{env, format, fs, process};
+
+    /// The mailbox `source` assembles to, up to its last filled cell.
+    fn cells(source: &str) -> Vec<u16> {
+        let sources = Sources::new();
+        let program = assemble(&sources, source).unwrap();
+        let size = program.opcodes.iter().rposition(Option::is_some).unwrap() + 1;
+        (0..size).map(|address| program.mailbox[address]).collect()
+    }
+
+    /// Writes `files` to a fresh directory named after `test` and returns it.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lmc-{}-{}", test, process::id()));
+        for (name, text) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    /// Assembles `file` of `dir`, which `INCLUDE` paths are resolved from.
+    fn assemble_file<'a>(
+        sources: &'a Sources<'a>,
+        text: &'a str,
+        dir: &Path,
+        file: &str,
+    ) -> Result<AssembledProgram<'a>, Diagnostics<'a>> {
+        let options = AssemblerOptions {
+            file_name: Some(dir.join(file).display().to_string()),
+            ..Default::default()
+        };
+        assemble_with_options(sources, text, &options)
+    }
+
+    /// Names of the errors `source` fails to assemble with, in order.
+    fn errors(source: &str) -> Vec<&'static str> {
+        let sources = Sources::new();
+        match assemble(&sources, source) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics
+                .errors
+                .iter()
+                .map(AssemblerError::name)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn labels_on_lines_without_operand_keep_their_address() {
+        assert_eq!(cells("START INP\nOUT\nBRA START"), [901, 902, 600]);
+        assert_eq!(cells("BRA END\nEND HLT"), [601, 0]);
+    }
+
+    #[test]
+    fn pool_fills_gap_before_org() {
+        let program = cells("LDA =5\nSTA DISP\nHLT\nORG 98\nSEP DAT\nDISP DAT");
+        assert_eq!(program[..4], [503, 399, 0, 5]);
+        assert_eq!(program.len(), 100);
+    }
+
+    #[test]
+    fn pool_spreads_over_free_cells() {
+        let program = cells("ORG 1\nLDA =1\nADD =2\nORG 4\nDS 96");
+        assert_eq!(program[..4], [1, 500, 103, 2]);
+    }
+
+    #[test]
+    fn pool_overflow_counts_every_cell() {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, "LDA =1\nADD =2\nDS 98") else {
+            panic!("the pool should not fit");
+        };
+        assert!(matches!(
+            diagnostics.errors[..],
+            [ConstantPoolTooLarge(_, 102)]
+        ));
+    }
+
+    #[test]
+    fn constants_are_read_only() {
+        assert_eq!(cells("LDA =5\nADD =5\nSUB =5"), [503, 103, 203, 5]);
+        for operation in ["STA", "INP", "BRA", "BRZ", "BRP"] {
+            let sources = Sources::new();
+            let source = format!("LDA =5\n{operation} =5");
+            let Err(diagnostics) = assemble(&sources, &source) else {
+                panic!("{operation} should not take a constant");
+            };
+            assert!(matches!(
+                &diagnostics.errors[..],
+                [AssemblerError::ConstantDestination(_, name)] if name == operation
+            ));
+        }
+    }
+
+    #[test]
+    fn pseudo_ops_only_read_constants() {
+        assert_eq!(cells("MOV =5, X\nHLT\nX DAT"), [504, 303, 0, 0, 5]);
+        for source in [
+            "INC =1",
+            "DEC =1",
+            "CLR =0",
+            "MOV X, =5",
+            "JZ =5",
+            "CALL =5",
+        ] {
+            let sources = Sources::new();
+            let source = format!("{source}\nHLT\nX DAT");
+            let Err(diagnostics) = assemble(&sources, &source) else {
+                panic!("{source:?} should not take a constant destination");
+            };
+            assert!(matches!(
+                &diagnostics.errors[..],
+                [AssemblerError::ConstantDestination(..)]
+            ));
+        }
+    }
+
+    #[test]
+    fn suggestions_skip_internal_labels() {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, "LDA =5\nJNZ X\nADD Y5\nX HLT") else {
+            panic!("Y5 is not defined");
+        };
+        let [error] = &diagnostics.errors[..] else {
+            panic!("expected one error, got {:?}", diagnostics.errors);
+        };
+        assert!(matches!(error, AssemblerError::UnsetLabel(..)));
+        assert_eq!(error.info().hint, None);
+    }
+
+    #[test]
+    fn ret_ignores_label_on_its_line() {
+        let program = cells("CALL FN\nHLT\nFN DAT\nZ RET");
+        assert_eq!(program, [506, 304, 605, 0, 0, 604, 603]);
+    }
+
+    #[test]
+    fn ret_needs_called_data_cell() {
+        for source in ["HLT\nFN DAT\nRET", "CALL FN\nHLT\nFN LDA 5\nRET"] {
+            let sources = Sources::new();
+            let Err(diagnostics) = assemble(&sources, source) else {
+                panic!("{source:?} should not assemble");
+            };
+            assert!(matches!(
+                &diagnostics.errors[..],
+                [AssemblerError::NotASubroutine(_, name)] if name == "FN"
+            ));
+        }
+    }
+
+    #[test]
+    fn operands_cannot_name_generated_labels() {
+        let sources = Sources::new();
+        let source = "MAIN JNZ MAIN\n LDA MAIN.x\n LDA @JNZ1\n.x DAT";
+        let Err(diagnostics) = assemble(&sources, source) else {
+            panic!("generated labels should not be referenced");
+        };
+        assert_eq!(diagnostics.errors.len(), 1);
+        assert_eq!(diagnostics.errors[0].info().line, 2);
+    }
+
+    #[test]
+    fn macros_expand_with_arguments_and_own_local_labels() {
+        let source = "MACRO BUMP V\n LDA V\n BRZ .skip\n ADD ONE\n.skip STA V\nENDM\n BUMP A\n BUMP B\n HLT\nA DAT 0\nB DAT 5\nONE DAT 1";
+        assert_eq!(
+            cells(source),
+            [509, 703, 111, 309, 510, 707, 111, 310, 0, 0, 5, 1]
+        );
+    }
+
+    #[test]
+    fn macro_errors_point_at_the_invocation() {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, "MACRO LOAD\n LDA NOPE\nENDM\n HLT\n LOAD")
+        else {
+            panic!("NOPE is not defined");
+        };
+        let [error] = &diagnostics.errors[..] else {
+            panic!("expected one error, got {:?}", diagnostics.errors);
+        };
+        let info = error.info();
+        let expansion = info.expansion.as_ref().unwrap();
+        assert_eq!((info.line, expansion.macro_name), (1, "LOAD"));
+        assert_eq!(expansion.invocation.line, 4);
+    }
+
+    #[test]
+    fn macro_definitions_are_checked() {
+        assert_eq!(errors("MACRO M A\nENDM\n M"), ["MacroArgumentCount"]);
+        assert_eq!(errors("MACRO M\nENDM\nMACRO M\nENDM"), ["MacroRedefined"]);
+        assert_eq!(errors("MACRO M\nMACRO N\nENDM"), ["NestedMacroDefinition"]);
+        assert_eq!(errors("HLT\nENDM"), ["UnexpectedEndOfMacro"]);
+        assert_eq!(errors("MACRO M\nHLT"), ["UnterminatedMacro"]);
+    }
+
+    #[test]
+    fn includes_resolve_from_the_including_file() {
+        let dir = write_files(
+            "include",
+            &[
+                ("lib/print.txt", " OUT\n INCLUDE \"more.txt\""),
+                ("lib/more.txt", " OUT"),
+            ],
+        );
+        let sources = Sources::new();
+        let source = " LDA X\n INCLUDE \"lib/print.txt\"\n HLT\nX DAT 7";
+        let program = assemble_file(&sources, source, &dir, "main.txt").unwrap();
+        let cells: Vec<u16> = (0..5usize)
+            .map(|address| program.mailbox[address])
+            .collect();
+        assert_eq!(cells, [504, 902, 902, 0, 7]);
+    }
+
+    #[test]
+    fn include_errors_name_their_file_in_include_order() {
+        let dir = write_files("include-errors", &[("z.txt", " LDA ONE")]);
+        let sources = Sources::new();
+        let source = " INCLUDE \"z.txt\"\n LDA TWO\n INCLUDE \"missing.txt\"";
+        let Err(diagnostics) = assemble_file(&sources, source, &dir, "main.txt") else {
+            panic!("ONE and TWO are not defined");
+        };
+        let files: Vec<(&str, &str)> = diagnostics
+            .errors
+            .iter()
+            .map(|error| (error.name(), error.info().file().unwrap()))
+            .collect();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].0, "UnsetLabel");
+        assert!(files[0].1.ends_with("z.txt"));
+        assert_eq!(files[1].0, "UnsetLabel");
+        assert!(files[1].1.ends_with("main.txt"));
+        assert_eq!(files[2].0, "IncludeNotFound");
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let dir = write_files(
+            "include-cycle",
+            &[
+                ("a.txt", " INCLUDE \"b.txt\""),
+                ("b.txt", " INCLUDE \"a.txt\""),
+            ],
+        );
+        let sources = Sources::new();
+        let Err(diagnostics) =
+            assemble_file(&sources, " INCLUDE \"a.txt\"\n HLT", &dir, "main.txt")
+        else {
+            panic!("a.txt includes itself through b.txt");
+        };
+        let names: Vec<&str> = diagnostics
+            .errors
+            .iter()
+            .map(AssemblerError::name)
+            .collect();
+        assert_eq!(names, ["IncludeCycle"]);
+    }
+
+    #[test]
+    fn expression_results_are_range_checked() {
+        assert_eq!(cells("LDA X+2\nX DAT 3, 4, 5"), [503, 3, 4, 5]);
+        assert_eq!(errors("LDA X+99\nX DAT"), ["OperandOutOfRange"]);
+        assert_eq!(errors("LDA X-2\nX DAT"), ["OperandOutOfRange"]);
+        assert_eq!(errors("DAT 999+1"), ["OperandOutOfRange"]);
+    }
+
+    #[test]
+    fn org_moves_the_location_counter() {
+        let program = cells("LDA X\nORG 10\nX DAT 7\nORG 3\nOUT");
+        assert_eq!(program, [510, 0, 0, 902, 0, 0, 0, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn org_reports_overlaps_and_overflow() {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, "ORG 5\nHLT\nORG 4\nLDA 1\nOUT") else {
+            panic!("address 5 is filled twice");
+        };
+        let [error] = &diagnostics.errors[..] else {
+            panic!("expected one error, got {:?}", diagnostics.errors);
+        };
+        assert!(matches!(error, OverlappingAddress(..)));
+        assert_eq!(error.info().line, 4);
+        assert_eq!(error.related().unwrap().1.line, 1);
+        assert_eq!(errors("ORG 100\nHLT"), ["OperandOutOfRange"]);
+        assert_eq!(errors("ORG 99\nHLT\nHLT"), ["ProgramTooLarge"]);
+    }
+
+    #[test]
+    fn equ_names_values_without_cells() {
+        assert_eq!(cells("STA LED\nHLT\nLED EQU 99"), [399, 0]);
+        assert_eq!(cells("A EQU 1\nB EQU A+1\nLDA B"), [502]);
+        let sources = Sources::new();
+        let program = assemble(&sources, "LED EQU 99\nHLT").unwrap();
+        assert!(!program.labels.contains_key("LED"));
+    }
+
+    #[test]
+    fn equ_names_are_checked() {
+        assert_eq!(errors("A EQU 1\nA EQU 2\nHLT"), ["Redefined"]);
+        assert_eq!(errors("A EQU 1\nA HLT"), ["Redefined"]);
+        assert_eq!(errors("EQU 5"), ["DirectiveExpectsName"]);
+        assert_eq!(errors("A EQU"), ["DirectiveExpectsOperand"]);
+    }
+
+    #[test]
+    fn strings_fill_one_cell_per_character() {
+        assert_eq!(cells("DAT \"Hi\\0\"\nDAT 'A'"), [72, 105, 0, 65]);
+        assert_eq!(cells(r#"DAT "a\"b\n""#), [97, 34, 98, 10]);
+        assert_eq!(cells("LDA 'A'-60"), [505]);
+    }
+
+    #[test]
+    fn strings_report_encoding_errors() {
+        assert_eq!(errors("DAT \"é\""), ["UnencodableCharacter"]);
+        assert_eq!(errors("DAT 'AB'"), ["UnencodableCharacter"]);
+        assert_eq!(errors("DAT \"abc"), ["UnterminatedString"]);
+        assert_eq!(errors("DAT \"a\" 5"), ["EndOfLineExpected"]);
+    }
+
+    #[test]
+    fn duplicate_labels_point_at_both_definitions() {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, "A HLT\nA DAT") else {
+            panic!("A is defined twice");
+        };
+        let [error] = &diagnostics.errors[..] else {
+            panic!("expected one error, got {:?}", diagnostics.errors);
+        };
+        assert_eq!(error.name(), "Redefined");
+        assert_eq!(error.info().line, 1);
+        assert_eq!(error.related().unwrap().1.line, 0);
+    }
+
+    #[test]
+    fn labels_must_be_identifiers_and_not_mnemonics() {
+        assert_eq!(cells("A_1 BRA B2\nB2 BRA A_1"), [601, 600]);
+        assert_eq!(errors("LDA HLT"), ["ReservedLabel"]);
+        assert_eq!(errors("HLT LDA 5"), ["ReservedLabel"]);
+        assert_eq!(errors("1A HLT"), ["InvalidLabel"]);
+        assert_eq!(errors("A-B HLT"), ["InvalidLabel"]);
+    }
+
+    #[test]
+    fn local_labels_belong_to_the_global_label_above() {
+        let source = "MAIN LDA .x\n BRA OTHER.x\n.x DAT 1\nOTHER LDA .x\n.x DAT 2";
+        assert_eq!(cells(source), [502, 604, 1, 504, 2]);
+        let sources = Sources::new();
+        let program = assemble(&sources, source).unwrap();
+        assert_eq!(program.labels.get("MAIN.x"), Some(&2));
+        assert_eq!(program.labels.get("OTHER.x"), Some(&4));
+    }
+
+    #[test]
+    fn local_labels_outside_their_scope_are_reported() {
+        assert_eq!(errors(".x HLT"), ["LocalLabelWithoutScope"]);
+        assert_eq!(
+            errors("MAIN LDA .x\n.x DAT\nB LDA .x"),
+            ["LocalLabelOutOfScope"]
+        );
+        assert_eq!(errors("MAIN LDA MAIN.y\n.x DAT"), ["UnsetLabel"]);
+    }
+
+    #[test]
+    fn conditionals_test_defines_and_constants() {
+        let source = "IF TARGET = microbit\n STA 99\nELSE\n OUT\nENDIF\n HLT";
+        let sources = Sources::new();
+        let options = AssemblerOptions {
+            defines: HashMap::from([("TARGET".to_string(), "microbit".to_string())]),
+            ..Default::default()
+        };
+        let program = assemble_with_options(&sources, source, &options).unwrap();
+        assert_eq!([program.mailbox[0usize], program.mailbox[1usize]], [399, 0]);
+        assert_eq!(cells(source), [902, 0]);
+        assert_eq!(cells("DEBUG EQU 1\nIF DEBUG\n OUT\nENDIF\n HLT"), [902, 0]);
+        assert_eq!(cells("DEBUG EQU 0\nIF DEBUG\n OUT\nENDIF\n OUT"), [902]);
+    }
+
+    #[test]
+    fn disabled_blocks_are_not_checked() {
+        assert_eq!(cells("IF X\n LDAA 5 !!\nIF\nENDIF\nENDIF\n OUT"), [902]);
+        assert_eq!(errors("ENDIF"), ["UnmatchedConditional"]);
+        assert_eq!(errors("IF X\nELSE\nELSE\nENDIF"), ["DuplicateElse"]);
+        assert_eq!(errors("IF X\n OUT"), ["UnterminatedIf"]);
+        assert_eq!(errors("IF =\nENDIF"), ["InvalidCondition"]);
     }
 }
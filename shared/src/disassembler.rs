@@ -0,0 +1,137 @@
+use crate::{Mailbox, OpCode};
+use std::fmt::Write;
+use std::format;
+use std::string::{String, ToString};
+use std::vec;
+use std::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CellKind {
+    Unknown,
+    Code,
+}
+
+/// Follows every path the program counter can take from address 0 and marks the
+/// cells it reaches as code. Everything else is data.
+fn find_code(mailbox: &Mailbox) -> [CellKind; 100] {
+    let mut kinds = [CellKind::Unknown; 100];
+    let mut pending = vec![0u16];
+    while let Some(address) = pending.pop() {
+        if address > 99 || kinds[address as usize] == CellKind::Code {
+            continue;
+        }
+        let Ok(opcode) = OpCode::try_from(mailbox[address]) else {
+            continue;
+        };
+        kinds[address as usize] = CellKind::Code;
+        match opcode {
+            OpCode::BRA(Some(target)) => pending.push(target),
+            OpCode::BRZ(Some(target)) | OpCode::BRP(Some(target)) => {
+                pending.push(target);
+                pending.push(address + 1);
+            }
+            OpCode::HLT(_) | OpCode::COB(_) | OpCode::DAT(_) => {}
+            _ => pending.push(address + 1),
+        }
+    }
+    kinds
+}
+
+/// Turns a mailbox back into LMC assembly that assembles to the identical mailbox.
+///
+/// Cells reachable from address 0 are written as instructions and every other cell as
+/// `DAT`. Branch targets get `L` labels and other referenced cells get `D` labels.
+pub fn disassemble(mailbox: &Mailbox) -> String {
+    let kinds = find_code(mailbox);
+    let targets: Vec<(u16, bool)> = (0..100u16)
+        .filter(|address| kinds[*address as usize] == CellKind::Code)
+        .filter_map(|address| OpCode::try_from(mailbox[address]).ok())
+        .filter_map(|opcode| {
+            let is_branch = matches!(opcode, OpCode::BRA(_) | OpCode::BRZ(_) | OpCode::BRP(_));
+            opcode.get_address().map(|target| (target, is_branch))
+        })
+        .collect();
+    let mut length = (0..100u16)
+        .rev()
+        .find(|address| mailbox[*address] != 0 || kinds[*address as usize] == CellKind::Code)
+        .map_or(0, |address| address + 1);
+    // Zeroed variables right after the program are kept so they still get a name.
+    while length < 100 && targets.iter().any(|(target, _)| *target == length) {
+        length += 1;
+    }
+
+    let mut labels: Vec<Option<String>> = vec![None; 100];
+    for (target, is_branch) in targets {
+        if target < length && labels[target as usize].is_none() {
+            let prefix = if is_branch || kinds[target as usize] == CellKind::Code {
+                "L"
+            } else {
+                "D"
+            };
+            labels[target as usize] = Some(format!("{}{:02}", prefix, target));
+        }
+    }
+
+    let mut output = String::new();
+    for address in 0..length {
+        let label = labels[address as usize].as_deref().unwrap_or("");
+        let value = mailbox[address];
+        let instruction = match (kinds[address as usize], OpCode::try_from(value)) {
+            (CellKind::Code, Ok(opcode)) => match *opcode.get_address() {
+                Some(target) => {
+                    let operand = labels
+                        .get(target as usize)
+                        .and_then(Option::clone)
+                        .unwrap_or_else(|| target.to_string());
+                    format!("{} {}", opcode.mnemonic_type(), operand)
+                }
+                None => format!("{}", opcode.mnemonic_type()),
+            },
+            _ => format!("DAT {}", value),
+        };
+        let _ = writeln!(output, "{:<8}{}", label, instruction);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+
+    /// Asserts that the disassembly of `mailbox` assembles back to it.
+    fn assert_round_trip(mailbox: &Mailbox) {
+        let text = disassemble(mailbox);
+        let sources = Sources::new();
+        let program = assemble(&sources, &text).unwrap();
+        for address in 0..100usize {
+            assert_eq!(program.mailbox[address], mailbox[address], "{text}");
+        }
+    }
+
+    #[test]
+    fn examples_round_trip() {
+        let examples = [
+            include_str!("../../examples/array.txt"),
+            include_str!("../../examples/ascii.txt"),
+            include_str!("../../examples/countdown.txt"),
+            include_str!("../../examples/led_countdown.txt"),
+            include_str!("../../examples/quine.txt"),
+            include_str!("../../examples/quine_led.txt"),
+            include_str!("../../examples/square.txt"),
+        ];
+        for example in examples {
+            let sources = Sources::new();
+            assert_round_trip(&assemble(&sources, example).unwrap().mailbox);
+        }
+    }
+
+    #[test]
+    fn data_and_invalid_cells_round_trip() {
+        let mut cells = [0; 100];
+        cells[..9].copy_from_slice(&[506, 107, 308, 901, 902, 0, 1, 400, 999]);
+        cells[99] = 5;
+        assert_round_trip(&Mailbox::from(cells));
+    }
+}
@@ -1,96 +1,847 @@
+use crate::lexer::{is_generated_label, is_pool_label, Invocation, LineStructure, SourceLine};
+use crate::pseudo::PseudoOp;
 use crate::MemonicType;
+use std::boxed::Box;
 use std::fmt::{Display, Formatter};
-use std::io::{BufRead, Lines};
+use std::format;
 use std::string::{String, ToString};
-#[derive(Debug)]
-pub struct ErrorInfo {
+use std::vec;
+use std::vec::Vec;
+#[derive(Debug, Clone)]
+pub struct ErrorInfo<'a> {
     pub start: usize,
     pub end: usize,
     pub line: u16,
-    pub literal: String,
+    pub source: SourceLine<'a>,
+    /// Where the macro containing this line was invoked, if the line comes from one.
+    pub expansion: Option<Box<Expansion<'a>>>,
+    /// Advice shown under the caret line.
+    pub hint: Option<Box<Hint>>,
 }
-impl ErrorInfo {
-    pub fn new<T: BufRead>(start: usize, end: usize, line: u16, source: &mut Lines<T>) -> Self {
-        Self {
+#[derive(Debug, Clone)]
+pub struct Expansion<'a> {
+    pub macro_name: &'a str,
+    pub invocation: ErrorInfo<'a>,
+}
+impl<'a> ErrorInfo<'a> {
+    pub fn new(start: usize, end: usize, line: &LineStructure<'a>) -> Self {
+        let info = Self {
             start,
             end,
-            line,
-            literal: source.nth(line as usize).unwrap().unwrap().to_string(),
+            line: line.line,
+            source: line.source.clone(),
+            expansion: None,
+            hint: None,
+        };
+        match &line.invocation {
+            Some(invocation) => info.with_invocation(invocation),
+            None => info,
+        }
+    }
+    /// File the line belongs to, `None` for an unnamed program.
+    pub fn file(&self) -> Option<&'a str> {
+        self.source.file_name()
+    }
+    pub fn literal(&self) -> &'a str {
+        self.source.literal()
+    }
+    /// `start` and `end` counted in characters of the line rather than bytes.
+    pub fn columns(&self) -> (usize, usize) {
+        let literal = self.literal();
+        let column = |offset: usize| match literal.get(..offset) {
+            Some(before) => before.chars().count(),
+            None => offset,
+        };
+        (column(self.start), column(self.end))
+    }
+    pub fn with_invocation(mut self, invocation: &Invocation<'a>) -> Self {
+        self.expansion = Some(Box::new(Expansion {
+            macro_name: invocation.name,
+            invocation: invocation.error_info(),
+        }));
+        self
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hint {
+    /// A known name close to the one that was written.
+    DidYouMean(String),
+}
+impl Hint {
+    /// Suggests the candidate closest to `name`, if any is close enough to be a likely typo.
+    /// Case is ignored, so `lda` suggests `LDA`.
+    pub fn did_you_mean<'a>(
+        name: &str,
+        candidates: impl IntoIterator<Item = &'a str>,
+    ) -> Option<Self> {
+        let name = name.to_ascii_uppercase();
+        let max_distance = (name.len() / 3).max(1);
+        candidates
+            .into_iter()
+            .map(|candidate| {
+                (
+                    edit_distance(&name, &candidate.to_ascii_uppercase()),
+                    candidate,
+                )
+            })
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min()
+            .map(|(_, candidate)| Hint::DidYouMean(candidate.to_string()))
+    }
+}
+impl Display for Hint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hint::DidYouMean(name) => write!(f, "Did you mean {}?", name),
+        }
+    }
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
+        previous = current;
     }
+    previous[b.len()]
 }
+
 fn show_code_and_point_at_position(
     f: &mut Formatter<'_>,
     position: &ErrorInfo,
 ) -> std::fmt::Result {
-    writeln!(f, "{}", position.literal)?;
-    write!(
-        f,
-        "{}",
-        " ".repeat(position.start) + &*"^".repeat(position.end - position.start)
-    )
+    if let Some(file) = position.file() {
+        writeln!(f, " --> {}:{}", file, position.line + 1)?;
+    }
+    writeln!(f, "{}", position.literal())?;
+    let (start, end) = position.columns();
+    write!(f, "{}", " ".repeat(start) + &*"^".repeat(end - start))?;
+    if let Some(hint) = &position.hint {
+        writeln!(f)?;
+        write!(f, "{}", hint)?;
+    }
+    if let Some(expansion) = &position.expansion {
+        writeln!(f)?;
+        writeln!(
+            f,
+            "In expansion of macro {} at line {}",
+            expansion.macro_name,
+            expansion.invocation.line + 1
+        )?;
+        show_code_and_point_at_position(f, &expansion.invocation)?;
+    }
+    Ok(())
 }
 #[derive(Debug)]
-pub enum AssemblerError {
-    InstructionExpected(ErrorInfo),
-    InstructionExpectedGotLabels(ErrorInfo),
-    EndOfLineExpected(ErrorInfo),
-    UnsetLabel(ErrorInfo, String),
-    UnexpectedInstruction(ErrorInfo, MemonicType),
-    InstructionExpectedAddress(ErrorInfo, MemonicType),
-    InvalidInstruction(ErrorInfo, String),
+pub enum AssemblerError<'a> {
+    InstructionExpected(ErrorInfo<'a>),
+    InstructionExpectedGotLabels(ErrorInfo<'a>),
+    EndOfLineExpected(ErrorInfo<'a>),
+    UnsetLabel(ErrorInfo<'a>, String),
+    UnexpectedInstruction(ErrorInfo<'a>, MemonicType),
+    InstructionExpectedAddress(ErrorInfo<'a>, MemonicType),
+    InvalidInstruction(ErrorInfo<'a>, String),
+    MacroNameExpected(ErrorInfo<'a>),
+    MacroRedefined(ErrorInfo<'a>, String),
+    NestedMacroDefinition(ErrorInfo<'a>),
+    UnexpectedEndOfMacro(ErrorInfo<'a>),
+    UnterminatedMacro(ErrorInfo<'a>, String),
+    MacroArgumentCount(ErrorInfo<'a>, String, u16, u16),
+    IncludePathExpected(ErrorInfo<'a>),
+    IncludeNotFound(ErrorInfo<'a>, String),
+    IncludeCycle(ErrorInfo<'a>, String),
+    InvalidExpression(ErrorInfo<'a>),
+    /// The operand evaluated to a value outside `0..=max`.
+    OperandOutOfRange(ErrorInfo<'a>, i32, u16),
+    DirectiveExpectsOperand(ErrorInfo<'a>, String),
+    DirectiveExpectsName(ErrorInfo<'a>, String),
+    /// A label or constant defined again, the second field is the first definition.
+    Redefined(ErrorInfo<'a>, Box<ErrorInfo<'a>>, String),
+    ReservedLabel(ErrorInfo<'a>, String),
+    InvalidLabel(ErrorInfo<'a>, String),
+    UnterminatedString(ErrorInfo<'a>),
+    UnencodableCharacter(ErrorInfo<'a>, String),
+    InvalidAddress(ErrorInfo<'a>, String),
+    AddressOutOfRange(ErrorInfo<'a>, String),
+    LiteralOutOfRange(ErrorInfo<'a>, String),
+    /// The program needs the given number of cells, more than the mailbox has.
+    ProgramTooLarge(ErrorInfo<'a>, usize),
+    /// A line was placed at an address already used by the line given in the second field.
+    OverlappingAddress(ErrorInfo<'a>, Box<ErrorInfo<'a>>, u16),
+    /// A local label defined or used before any global label.
+    LocalLabelWithoutScope(ErrorInfo<'a>, String),
+    /// A local label used under a global label, the last field, that does not define it.
+    LocalLabelOutOfScope(ErrorInfo<'a>, String, String),
+    /// `ELSE` or `ENDIF`, the second field, without an `IF` before it.
+    UnmatchedConditional(ErrorInfo<'a>, String),
+    /// A second `ELSE` in the same `IF` block.
+    DuplicateElse(ErrorInfo<'a>),
+    /// An `IF` without a matching `ENDIF`.
+    UnterminatedIf(ErrorInfo<'a>),
+    /// An `IF` not followed by `NAME`, `NAME = value` or `NAME != value`.
+    InvalidCondition(ErrorInfo<'a>),
+    /// An operand of an object that depends on more than one label or on a label more than
+    /// once, so the linker cannot fix it up.
+    NotRelocatable(ErrorInfo<'a>),
+    /// The code fits in the mailbox but its constant pool, needing the given number of cells
+    /// in all, does not.
+    ConstantPoolTooLarge(ErrorInfo<'a>, usize),
+    /// The count of a `DS` or of a repeated `DAT` value is not from 1 to 100.
+    InvalidCount(ErrorInfo<'a>, String),
+    /// A pseudo-instruction with the wrong number of operands.
+    InvalidPseudoOperands(ErrorInfo<'a>, PseudoOp),
+    /// A `RET` before any global label, so there is no subroutine to return from.
+    ReturnWithoutSubroutine(ErrorInfo<'a>),
+    /// A `RET` under the global label in the second field, which does not name a `DAT` cell
+    /// given to `CALL`.
+    NotASubroutine(ErrorInfo<'a>, String),
+    /// A file with more lines than the given maximum, pointing at the last line read.
+    TooManyLines(ErrorInfo<'a>, u16),
+    /// A `=value` constant given to the operation in the second field, which writes to or
+    /// branches to its operand.
+    ConstantDestination(ErrorInfo<'a>, String),
 }
-
-impl Display for AssemblerError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> AssemblerError<'a> {
+    pub fn info(&self) -> &ErrorInfo<'a> {
+        match self {
+            AssemblerError::InstructionExpected(info)
+            | AssemblerError::InstructionExpectedGotLabels(info)
+            | AssemblerError::EndOfLineExpected(info)
+            | AssemblerError::UnsetLabel(info, _)
+            | AssemblerError::UnexpectedInstruction(info, _)
+            | AssemblerError::InstructionExpectedAddress(info, _)
+            | AssemblerError::InvalidInstruction(info, _)
+            | AssemblerError::MacroNameExpected(info)
+            | AssemblerError::MacroRedefined(info, _)
+            | AssemblerError::NestedMacroDefinition(info)
+            | AssemblerError::UnexpectedEndOfMacro(info)
+            | AssemblerError::UnterminatedMacro(info, _)
+            | AssemblerError::MacroArgumentCount(info, _, _, _)
+            | AssemblerError::IncludePathExpected(info)
+            | AssemblerError::IncludeNotFound(info, _)
+            | AssemblerError::IncludeCycle(info, _)
+            | AssemblerError::InvalidExpression(info)
+            | AssemblerError::OperandOutOfRange(info, _, _)
+            | AssemblerError::DirectiveExpectsOperand(info, _)
+            | AssemblerError::DirectiveExpectsName(info, _)
+            | AssemblerError::Redefined(info, _, _)
+            | AssemblerError::ReservedLabel(info, _)
+            | AssemblerError::InvalidLabel(info, _)
+            | AssemblerError::UnterminatedString(info)
+            | AssemblerError::UnencodableCharacter(info, _)
+            | AssemblerError::InvalidAddress(info, _)
+            | AssemblerError::AddressOutOfRange(info, _)
+            | AssemblerError::LiteralOutOfRange(info, _)
+            | AssemblerError::ProgramTooLarge(info, _)
+            | AssemblerError::OverlappingAddress(info, _, _)
+            | AssemblerError::LocalLabelWithoutScope(info, _)
+            | AssemblerError::LocalLabelOutOfScope(info, _, _)
+            | AssemblerError::UnmatchedConditional(info, _)
+            | AssemblerError::DuplicateElse(info)
+            | AssemblerError::UnterminatedIf(info)
+            | AssemblerError::InvalidCondition(info)
+            | AssemblerError::NotRelocatable(info)
+            | AssemblerError::ConstantPoolTooLarge(info, _)
+            | AssemblerError::InvalidCount(info, _)
+            | AssemblerError::InvalidPseudoOperands(info, _)
+            | AssemblerError::ReturnWithoutSubroutine(info)
+            | AssemblerError::NotASubroutine(info, _)
+            | AssemblerError::TooManyLines(info, _)
+            | AssemblerError::ConstantDestination(info, _) => info,
+        }
+    }
+    /// Stable code identifying the kind of error. Codes are never renumbered or reused.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AssemblerError::InstructionExpected(..) => "E0001",
+            AssemblerError::InstructionExpectedGotLabels(..) => "E0002",
+            AssemblerError::UnsetLabel(..) => "E0003",
+            AssemblerError::EndOfLineExpected(..) => "E0004",
+            AssemblerError::UnexpectedInstruction(..) => "E0005",
+            AssemblerError::InstructionExpectedAddress(..) => "E0006",
+            AssemblerError::InvalidInstruction(..) => "E0007",
+            AssemblerError::MacroNameExpected(..) => "E0008",
+            AssemblerError::MacroRedefined(..) => "E0009",
+            AssemblerError::NestedMacroDefinition(..) => "E0010",
+            AssemblerError::UnexpectedEndOfMacro(..) => "E0011",
+            AssemblerError::UnterminatedMacro(..) => "E0012",
+            AssemblerError::MacroArgumentCount(..) => "E0013",
+            AssemblerError::IncludePathExpected(..) => "E0014",
+            AssemblerError::IncludeNotFound(..) => "E0015",
+            AssemblerError::IncludeCycle(..) => "E0016",
+            AssemblerError::InvalidExpression(..) => "E0017",
+            AssemblerError::OperandOutOfRange(..) => "E0018",
+            AssemblerError::DirectiveExpectsOperand(..) => "E0019",
+            AssemblerError::DirectiveExpectsName(..) => "E0020",
+            AssemblerError::Redefined(..) => "E0021",
+            AssemblerError::ReservedLabel(..) => "E0022",
+            AssemblerError::InvalidLabel(..) => "E0023",
+            AssemblerError::UnterminatedString(..) => "E0024",
+            AssemblerError::UnencodableCharacter(..) => "E0025",
+            AssemblerError::InvalidAddress(..) => "E0026",
+            AssemblerError::AddressOutOfRange(..) => "E0027",
+            AssemblerError::LiteralOutOfRange(..) => "E0028",
+            AssemblerError::ProgramTooLarge(..) => "E0029",
+            AssemblerError::OverlappingAddress(..) => "E0030",
+            AssemblerError::LocalLabelWithoutScope(..) => "E0031",
+            AssemblerError::LocalLabelOutOfScope(..) => "E0032",
+            AssemblerError::UnmatchedConditional(..) => "E0033",
+            AssemblerError::DuplicateElse(..) => "E0034",
+            AssemblerError::UnterminatedIf(..) => "E0035",
+            AssemblerError::InvalidCondition(..) => "E0036",
+            AssemblerError::NotRelocatable(..) => "E0037",
+            AssemblerError::ConstantPoolTooLarge(..) => "E0038",
+            AssemblerError::InvalidCount(..) => "E0039",
+            AssemblerError::InvalidPseudoOperands(..) => "E0040",
+            AssemblerError::ReturnWithoutSubroutine(..) => "E0041",
+            AssemblerError::TooManyLines(..) => "E0042",
+            AssemblerError::NotASubroutine(..) => "E0043",
+            AssemblerError::ConstantDestination(..) => "E0044",
+        }
+    }
+    /// Name of the kind of error, shown next to its code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AssemblerError::InstructionExpected(..) => "InstructionExpected",
+            AssemblerError::InstructionExpectedGotLabels(..) => "InstructionExpectedGotLabels",
+            AssemblerError::UnsetLabel(..) => "UnsetLabel",
+            AssemblerError::EndOfLineExpected(..) => "EndOfLineExpected",
+            AssemblerError::UnexpectedInstruction(..) => "UnexpectedInstruction",
+            AssemblerError::InstructionExpectedAddress(..) => "InstructionExpectedAddress",
+            AssemblerError::InvalidInstruction(..) => "InvalidInstruction",
+            AssemblerError::MacroNameExpected(..) => "MacroNameExpected",
+            AssemblerError::MacroRedefined(..) => "MacroRedefined",
+            AssemblerError::NestedMacroDefinition(..) => "NestedMacroDefinition",
+            AssemblerError::UnexpectedEndOfMacro(..) => "UnexpectedEndOfMacro",
+            AssemblerError::UnterminatedMacro(..) => "UnterminatedMacro",
+            AssemblerError::MacroArgumentCount(..) => "MacroArgumentCount",
+            AssemblerError::IncludePathExpected(..) => "IncludePathExpected",
+            AssemblerError::IncludeNotFound(..) => "IncludeNotFound",
+            AssemblerError::IncludeCycle(..) => "IncludeCycle",
+            AssemblerError::InvalidExpression(..) => "InvalidExpression",
+            AssemblerError::OperandOutOfRange(..) => "OperandOutOfRange",
+            AssemblerError::DirectiveExpectsOperand(..) => "DirectiveExpectsOperand",
+            AssemblerError::DirectiveExpectsName(..) => "DirectiveExpectsName",
+            AssemblerError::Redefined(..) => "Redefined",
+            AssemblerError::ReservedLabel(..) => "ReservedLabel",
+            AssemblerError::InvalidLabel(..) => "InvalidLabel",
+            AssemblerError::UnterminatedString(..) => "UnterminatedString",
+            AssemblerError::UnencodableCharacter(..) => "UnencodableCharacter",
+            AssemblerError::InvalidAddress(..) => "InvalidAddress",
+            AssemblerError::AddressOutOfRange(..) => "AddressOutOfRange",
+            AssemblerError::LiteralOutOfRange(..) => "LiteralOutOfRange",
+            AssemblerError::ProgramTooLarge(..) => "ProgramTooLarge",
+            AssemblerError::OverlappingAddress(..) => "OverlappingAddress",
+            AssemblerError::LocalLabelWithoutScope(..) => "LocalLabelWithoutScope",
+            AssemblerError::LocalLabelOutOfScope(..) => "LocalLabelOutOfScope",
+            AssemblerError::UnmatchedConditional(..) => "UnmatchedConditional",
+            AssemblerError::DuplicateElse(..) => "DuplicateElse",
+            AssemblerError::UnterminatedIf(..) => "UnterminatedIf",
+            AssemblerError::InvalidCondition(..) => "InvalidCondition",
+            AssemblerError::NotRelocatable(..) => "NotRelocatable",
+            AssemblerError::ConstantPoolTooLarge(..) => "ConstantPoolTooLarge",
+            AssemblerError::InvalidCount(..) => "InvalidCount",
+            AssemblerError::InvalidPseudoOperands(..) => "InvalidPseudoOperands",
+            AssemblerError::ReturnWithoutSubroutine(..) => "ReturnWithoutSubroutine",
+            AssemblerError::TooManyLines(..) => "TooManyLines",
+            AssemblerError::NotASubroutine(..) => "NotASubroutine",
+            AssemblerError::ConstantDestination(..) => "ConstantDestination",
+        }
+    }
+    /// [`AssemblerError::UnsetLabel`] for `label`, or [`AssemblerError::LocalLabelOutOfScope`]
+    /// when it is a local label that only other global labels define. `known` are the
+    /// defined names, used for suggestions.
+    pub fn unset_label<'b>(
+        mut info: ErrorInfo<'a>,
+        label: &str,
+        known: impl IntoIterator<Item = &'b str>,
+    ) -> Self {
+        // Pool, pseudo-op and macro-local labels such as `=5`, `@JNZ1` and `.x@3` are never
+        // written by hand.
+        let mut known: Vec<&str> = known
+            .into_iter()
+            .filter(|name| !is_pool_label(name) && !is_generated_label(name) && !name.contains('@'))
+            .collect();
+        if let Some((scope, local)) = label
+            .find('.')
+            .filter(|index| *index > 0)
+            .map(|index| label.split_at(index))
+        {
+            known.sort_unstable();
+            let elsewhere = known
+                .iter()
+                .find(|name| name.find('.').is_some_and(|index| &name[index..] == local));
+            if let Some(elsewhere) = elsewhere {
+                info.hint = Some(Box::new(Hint::DidYouMean(elsewhere.to_string())));
+                return AssemblerError::LocalLabelOutOfScope(
+                    info,
+                    local.to_string(),
+                    scope.to_string(),
+                );
+            }
+        }
+        info.hint = Hint::did_you_mean(label, known).map(Box::new);
+        AssemblerError::UnsetLabel(info, label.to_string())
+    }
+    /// A second location the error refers to, with a note describing it.
+    pub fn related(&self) -> Option<(&'static str, &ErrorInfo<'a>)> {
+        match self {
+            AssemblerError::Redefined(_, previous, _) => Some(("First defined here", previous)),
+            AssemblerError::OverlappingAddress(_, previous, _) => {
+                Some(("First used here", previous))
+            }
+            _ => None,
+        }
+    }
+    /// The first line of the error, without the source it points at.
+    pub fn message(&self) -> String {
         match self {
             AssemblerError::InstructionExpected(info) => {
-                writeln!(f, "Instruction expected at line {}", info.line + 1)?;
-                show_code_and_point_at_position(f, info)
+                format!("Instruction expected at line {}", info.line + 1)
             }
             AssemblerError::InstructionExpectedGotLabels(info) => {
-                writeln!(
-                    f,
-                    "Instruction expected at line {}, got labels",
-                    info.line + 1
-                )?;
-                show_code_and_point_at_position(f, info)
+                format!("Instruction expected at line {}, got labels", info.line + 1)
             }
             AssemblerError::EndOfLineExpected(info) => {
-                writeln!(f, "End of line expected at line {}", info.line + 1)?;
-                show_code_and_point_at_position(f, info)
+                format!("End of line expected at line {}", info.line + 1)
             }
             AssemblerError::UnsetLabel(info, label) => {
-                writeln!(f, "Unset label {} at line {}", label, info.line + 1)?;
-                show_code_and_point_at_position(f, info)
+                format!("Unset label {} at line {}", label, info.line + 1)
             }
             AssemblerError::UnexpectedInstruction(info, label) => {
-                writeln!(
-                    f,
+                format!(
                     "Unexpected instruction: {} at line {}",
                     label,
                     info.line + 1
-                )?;
-                show_code_and_point_at_position(f, info)
+                )
             }
             AssemblerError::InstructionExpectedAddress(info, instruction) => {
-                writeln!(
-                    f,
+                format!(
                     "Instruction {} expects an address at line {}",
                     instruction,
                     info.line + 1
-                )?;
-                show_code_and_point_at_position(f, info)
+                )
             }
             AssemblerError::InvalidInstruction(info, instruction) => {
-                writeln!(
-                    f,
+                format!(
                     "Invalid instruction {} at line {}",
                     instruction,
                     info.line + 1
-                )?;
-                show_code_and_point_at_position(f, info)
+                )
+            }
+            AssemblerError::MacroNameExpected(info) => {
+                format!("Macro name expected at line {}", info.line + 1)
+            }
+            AssemblerError::MacroRedefined(info, name) => {
+                format!(
+                    "Macro {} is already defined, redefined at line {}",
+                    name,
+                    info.line + 1
+                )
+            }
+            AssemblerError::NestedMacroDefinition(info) => {
+                format!(
+                    "Macros cannot be defined inside another macro at line {}",
+                    info.line + 1
+                )
+            }
+            AssemblerError::UnexpectedEndOfMacro(info) => {
+                format!("ENDM without MACRO at line {}", info.line + 1)
+            }
+            AssemblerError::UnterminatedMacro(info, name) => {
+                format!(
+                    "Macro {} defined at line {} is missing ENDM",
+                    name,
+                    info.line + 1
+                )
+            }
+            AssemblerError::MacroArgumentCount(info, name, expected, found) => {
+                format!(
+                    "Macro {} expects {} arguments but got {} at line {}",
+                    name,
+                    expected,
+                    found,
+                    info.line + 1
+                )
+            }
+            AssemblerError::IncludePathExpected(info) => {
+                format!(
+                    "INCLUDE expects a quoted file path at line {}",
+                    info.line + 1
+                )
+            }
+            AssemblerError::IncludeNotFound(info, path) => {
+                format!(
+                    "Cannot read included file {} at line {}",
+                    path,
+                    info.line + 1
+                )
+            }
+            AssemblerError::IncludeCycle(info, path) => {
+                format!(
+                    "Including {} at line {} would create an include cycle",
+                    path,
+                    info.line + 1
+                )
+            }
+            AssemblerError::InvalidExpression(info) => {
+                format!("Invalid operand expression at line {}", info.line + 1)
+            }
+            AssemblerError::OperandOutOfRange(info, value, max) => {
+                format!(
+                    "Operand evaluates to {} at line {}, expected a value between 0 and {}",
+                    value,
+                    info.line + 1,
+                    max
+                )
+            }
+            AssemblerError::DirectiveExpectsOperand(info, directive) => {
+                format!("{} expects an operand at line {}", directive, info.line + 1)
+            }
+            AssemblerError::DirectiveExpectsName(info, directive) => {
+                format!(
+                    "{} expects a name before it at line {}",
+                    directive,
+                    info.line + 1
+                )
+            }
+            AssemblerError::Redefined(info, _, name) => {
+                format!(
+                    "{} is already defined, redefined at line {}",
+                    name,
+                    info.line + 1
+                )
+            }
+            AssemblerError::ReservedLabel(info, name) => {
+                format!(
+                    "{} is an instruction and cannot be used as a label at line {}",
+                    name,
+                    info.line + 1
+                )
+            }
+            AssemblerError::InvalidLabel(info, name) => {
+                format!(
+                    "Invalid label {} at line {}, labels start with a letter or _ followed by letters, digits or _, local labels with a .",
+                    name,
+                    info.line + 1
+                )
+            }
+            AssemblerError::UnterminatedString(info) => {
+                format!("Missing closing quote in literal at line {}", info.line + 1)
+            }
+            AssemblerError::UnencodableCharacter(info, literal) => {
+                format!(
+                    "{} cannot be encoded as a single ASCII character at line {}",
+                    literal,
+                    info.line + 1
+                )
             }
+            AssemblerError::InvalidAddress(info, literal) => {
+                format!(
+                    "{} is not a valid address at line {}, expected & followed by a number",
+                    literal,
+                    info.line + 1
+                )
+            }
+            AssemblerError::AddressOutOfRange(info, literal) => {
+                format!(
+                    "Address {} at line {} is outside the mailbox, expected 0 to 99",
+                    literal,
+                    info.line + 1
+                )
+            }
+            AssemblerError::LiteralOutOfRange(info, literal) => {
+                format!(
+                    "Value {} at line {} does not fit in a mailbox, expected 0 to 999",
+                    literal,
+                    info.line + 1
+                )
+            }
+            AssemblerError::ProgramTooLarge(info, needed) => {
+                format!(
+                    "Program needs {} cells but only 100 are available, line {} is past the end",
+                    needed,
+                    info.line + 1
+                )
+            }
+            AssemblerError::OverlappingAddress(info, previous, address) => {
+                format!(
+                    "Address {} at line {} is already used by line {}",
+                    address,
+                    info.line + 1,
+                    previous.line + 1
+                )
+            }
+            AssemblerError::LocalLabelWithoutScope(info, name) => format!(
+                "Local label {} at line {} has no global label before it to belong to",
+                name,
+                info.line + 1
+            ),
+            AssemblerError::LocalLabelOutOfScope(info, name, scope) => format!(
+                "Local label {} is not defined under {} at line {}",
+                name,
+                scope,
+                info.line + 1
+            ),
+            AssemblerError::UnmatchedConditional(info, keyword) => {
+                format!("{} without IF at line {}", keyword, info.line + 1)
+            }
+            AssemblerError::DuplicateElse(info) => {
+                format!("Second ELSE in the same IF at line {}", info.line + 1)
+            }
+            AssemblerError::UnterminatedIf(info) => {
+                format!("IF at line {} has no matching ENDIF", info.line + 1)
+            }
+            AssemblerError::InvalidCondition(info) => format!(
+                "Invalid condition at line {}, expected NAME, NAME = value or NAME != value",
+                info.line + 1
+            ),
+            AssemblerError::NotRelocatable(info) => format!(
+                "Operand at line {} cannot be linked, expected one label plus or minus a constant",
+                info.line + 1
+            ),
+            AssemblerError::ConstantPoolTooLarge(info, needed) => format!(
+                "Program and constant pool need {} cells but only 100 are available, the constant at line {} does not fit",
+                needed,
+                info.line + 1
+            ),
+            AssemblerError::InvalidCount(info, count) => format!(
+                "Invalid count {} at line {}, expected 1 to 100 cells",
+                count,
+                info.line + 1
+            ),
+            AssemblerError::InvalidPseudoOperands(info, pseudo) => format!(
+                "Wrong operands for {} at line {}, expected {}",
+                pseudo.name(),
+                info.line + 1,
+                pseudo.usage()
+            ),
+            AssemblerError::ReturnWithoutSubroutine(info) => format!(
+                "RET at line {} is not inside a subroutine, expected a global label before it",
+                info.line + 1
+            ),
+            AssemblerError::NotASubroutine(info, subroutine) => format!(
+                "RET at line {} returns from {}, which is not a DAT cell called with CALL",
+                info.line + 1,
+                subroutine
+            ),
+            AssemblerError::TooManyLines(info, max) => format!(
+                "File has more than {} lines, nothing after line {} is assembled",
+                max,
+                info.line + 1
+            ),
+            AssemblerError::ConstantDestination(info, operation) => format!(
+                "{} at line {} cannot take a constant, it would write to or branch into a cell other constants share",
+                operation,
+                info.line + 1
+            ),
+        }
+    }
+}
+
+impl Display for AssemblerError<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.message())?;
+        show_code_and_point_at_position(f, self.info())?;
+        if let Some((note, related)) = self.related() {
+            writeln!(f)?;
+            writeln!(f, "{}", note)?;
+            show_code_and_point_at_position(f, related)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarningKind {
+    UnusedLabel,
+    Unreachable,
+    Uninitialized,
+    SelfModifying,
+}
+impl WarningKind {
+    /// Name used to silence the warning with a `// @allow <name>` comment on the line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::UnusedLabel => "unused-label",
+            WarningKind::Unreachable => "unreachable",
+            WarningKind::Uninitialized => "uninitialized",
+            WarningKind::SelfModifying => "self-modifying",
+        }
+    }
+    /// Stable code identifying the warning. Codes are never renumbered or reused.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WarningKind::UnusedLabel => "W0001",
+            WarningKind::Unreachable => "W0002",
+            WarningKind::Uninitialized => "W0003",
+            WarningKind::SelfModifying => "W0004",
         }
     }
 }
+
+/// Something that assembles but is probably a mistake.
+#[derive(Debug)]
+pub enum AssemblerWarning<'a> {
+    UnusedLabel(ErrorInfo<'a>, String),
+    /// Code following the unconditional instruction in the second field.
+    UnreachableCode(ErrorInfo<'a>, MemonicType),
+    /// An instruction reading a cell reserved by `DS` that no `STA` stores to.
+    UninitializedRead(ErrorInfo<'a>, MemonicType, u16),
+    /// `LDA` or `STA` of an address holding code.
+    SelfModifying(ErrorInfo<'a>, MemonicType, u16),
+}
+impl<'a> AssemblerWarning<'a> {
+    pub fn info(&self) -> &ErrorInfo<'a> {
+        match self {
+            AssemblerWarning::UnusedLabel(info, _)
+            | AssemblerWarning::UnreachableCode(info, _)
+            | AssemblerWarning::UninitializedRead(info, _, _)
+            | AssemblerWarning::SelfModifying(info, _, _) => info,
+        }
+    }
+    pub fn kind(&self) -> WarningKind {
+        match self {
+            AssemblerWarning::UnusedLabel(..) => WarningKind::UnusedLabel,
+            AssemblerWarning::UnreachableCode(..) => WarningKind::Unreachable,
+            AssemblerWarning::UninitializedRead(..) => WarningKind::Uninitialized,
+            AssemblerWarning::SelfModifying(..) => WarningKind::SelfModifying,
+        }
+    }
+    /// The first line of the warning, without the source it points at.
+    pub fn message(&self) -> String {
+        match self {
+            AssemblerWarning::UnusedLabel(info, label) => {
+                format!("label {} is never used at line {}", label, info.line + 1)
+            }
+            AssemblerWarning::UnreachableCode(info, instruction) => format!(
+                "unreachable code after {} at line {}",
+                instruction,
+                info.line + 1
+            ),
+            AssemblerWarning::UninitializedRead(info, instruction, address) => format!(
+                "{} reads address {} at line {}, which is reserved by DS and never stored to",
+                instruction,
+                address,
+                info.line + 1
+            ),
+            AssemblerWarning::SelfModifying(info, instruction, address) => format!(
+                "{} accesses address {} at line {}, which holds code",
+                instruction,
+                address,
+                info.line + 1
+            ),
+        }
+    }
+}
+impl Display for AssemblerWarning<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Warning: {}", self.message())?;
+        show_code_and_point_at_position(f, self.info())?;
+        writeln!(f)?;
+        write!(
+            f,
+            "Add // @allow {} to the line to silence this warning",
+            self.kind().name()
+        )
+    }
+}
+
+/// Every error found in a program, in source order.
+#[derive(Debug)]
+pub struct Diagnostics<'a> {
+    pub errors: Vec<AssemblerError<'a>>,
+    /// Set when more errors were found than the configured cap allowed to keep.
+    pub truncated: bool,
+}
+impl<'a> Diagnostics<'a> {
+    pub fn new(mut errors: Vec<AssemblerError<'a>>, max_errors: usize) -> Self {
+        errors.sort_by_cached_key(|error| {
+            let info = error.info();
+            info.source.file.position(info.line)
+        });
+        let truncated = errors.len() > max_errors;
+        errors.truncate(max_errors);
+        Self { errors, truncated }
+    }
+}
+impl<'a> From<AssemblerError<'a>> for Diagnostics<'a> {
+    fn from(error: AssemblerError<'a>) -> Self {
+        Self {
+            errors: vec![error],
+            truncated: false,
+        }
+    }
+}
+impl Display for Diagnostics<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{}", error)?;
+            writeln!(f)?;
+        }
+        if self.truncated {
+            write!(f, "Stopped after {} errors", self.errors.len())
+        } else if self.errors.len() == 1 {
+            write!(f, "Found 1 error")
+        } else {
+            write!(f, "Found {} errors", self.errors.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+
+    /// The hint of the only error `source` fails to assemble with.
+    fn hint(source: &str) -> Option<Hint> {
+        let sources = Sources::new();
+        let Err(diagnostics) = assemble(&sources, source) else {
+            panic!("{source:?} should not assemble");
+        };
+        let [error] = &diagnostics.errors[..] else {
+            panic!("expected one error, got {:?}", diagnostics.errors);
+        };
+        error.info().hint.as_deref().cloned()
+    }
+
+    #[test]
+    fn suggests_the_closest_candidate() {
+        let candidates = ["LDA", "STA", "ADD"];
+        let suggest = |name| Hint::did_you_mean(name, candidates);
+        assert_eq!(suggest("LDAA"), Some(Hint::DidYouMean("LDA".to_string())));
+        assert_eq!(suggest("sta"), Some(Hint::DidYouMean("STA".to_string())));
+        assert_eq!(suggest("FOO"), None);
+        assert_eq!(edit_distance("COUNT", "COUTN"), 2);
+    }
+
+    #[test]
+    fn hints_misspelled_mnemonics_and_labels() {
+        let did_you_mean = |name: &str| Some(Hint::DidYouMean(name.to_string()));
+        assert_eq!(hint(" LDAA 5"), did_you_mean("LDA"));
+        assert_eq!(hint(" LDA COUNTT\nCOUNT DAT"), did_you_mean("COUNT"));
+        assert_eq!(hint("X FOO 5"), None);
+    }
+}
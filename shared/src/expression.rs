@@ -0,0 +1,481 @@
+//! Operand expressions such as `TABLE+1` or `(END-START)-1`.
+//!
+//! Numbers, labels, `&` addresses and character literals such as `'A'` can be combined
+//! with `+`, `-` and parentheses. Expressions are resolved by the assembler once every
+//! label has an address.
+//!
+//! Character and string literals accept the escapes `\0`, `\n`, `\t`, `\\`, `\'` and `\"`.
+use crate::lexer::{is_reference, LinePart, RightField};
+use std::borrow::Cow;
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression<'a> {
+    Operand(LinePart<RightField<'a>>),
+    Negate(Box<Expression<'a>>),
+    Add(Box<Expression<'a>>, Box<Expression<'a>>),
+    Subtract(Box<Expression<'a>>, Box<Expression<'a>>),
+}
+
+/// Column range of the part of an operand that could not be parsed.
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    /// The text is not a valid expression.
+    Invalid(usize, usize),
+    /// A valid expression is followed by more text.
+    Trailing(usize, usize),
+    /// A character or string literal is missing its closing quote.
+    Unterminated(usize, usize),
+    /// A character that has no ASCII code, or a character literal that is not one character.
+    Unencodable(usize, usize),
+    /// `&` is not followed by a number.
+    InvalidAddress(usize, usize),
+    /// An address outside `0..=99`.
+    AddressOutOfRange(usize, usize),
+    /// A number outside `0..=999`.
+    LiteralOutOfRange(usize, usize),
+    /// A repeat count that is not a number from 1 to 100.
+    InvalidCount(usize, usize),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Token<'a> {
+    Plus,
+    Minus,
+    Open,
+    Close,
+    Char(u16),
+    Atom(&'a str),
+}
+
+/// Decodes the characters of a literal up to the closing `quote`. `text` starts right after
+/// the opening quote, at column `offset`. Returns the codes with their columns and the
+/// length of the literal including the closing quote.
+fn decode(
+    text: &str,
+    offset: usize,
+    quote: char,
+) -> Result<(Vec<LinePart<u16>>, usize), ExpressionError> {
+    let mut codes = Vec::new();
+    let mut chars = text.char_indices();
+    while let Some((index, char)) = chars.next() {
+        let start = offset + index;
+        let (code, end) = match char {
+            char if char == quote => return Ok((codes, index + 1)),
+            '\\' => {
+                let Some((escape_index, escape)) = chars.next() else {
+                    break;
+                };
+                let end = offset + escape_index + escape.len_utf8();
+                let code = match escape {
+                    '0' => 0,
+                    'n' => 10,
+                    't' => 9,
+                    '\\' | '\'' | '"' => escape as u16,
+                    _ => return Err(ExpressionError::Unencodable(start, end)),
+                };
+                (code, end)
+            }
+            char if char.is_ascii() => (char as u16, start + 1),
+            char => return Err(ExpressionError::Unencodable(start, start + char.len_utf8())),
+        };
+        codes.push(LinePart {
+            start,
+            end,
+            value: code,
+        });
+    }
+    Err(ExpressionError::Unterminated(
+        offset - 1,
+        offset + text.len(),
+    ))
+}
+
+/// Parses a string literal such as `"Hello World\0"` into one character code per cell.
+pub fn parse_text(text: &str, offset: usize) -> Result<Vec<LinePart<u16>>, ExpressionError> {
+    let Some(body) = text.strip_prefix('"') else {
+        return Err(ExpressionError::Invalid(offset, offset + text.len()));
+    };
+    let (codes, length) = decode(body, offset + 1, '"')?;
+    let rest = &body[length..];
+    if let Some(trailing) = rest.find(|char: char| !char.is_whitespace()) {
+        let start = offset + 1 + length + trailing;
+        return Err(ExpressionError::Trailing(start, offset + text.len()));
+    }
+    Ok(codes)
+}
+
+/// Splits `text` at the commas outside of quotes, giving each part with its offset.
+fn split_list(text: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((index, char)) = chars.next() {
+        match (quote, char) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(open), char) if char == open => quote = None,
+            (None, '"' | '\'') => quote = Some(char),
+            (None, ',') => {
+                parts.push((part_start, &text[part_start..index]));
+                part_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push((part_start, &text[part_start..]));
+    parts
+}
+
+/// Parses the operand of a `DAT`: values separated by commas, each a string or an operand,
+/// optionally followed by `xN` to repeat it N times. A single value is returned as it is,
+/// several as a [`RightField::List`] with one value per cell.
+pub fn parse_data(text: &str, offset: usize) -> Result<RightField<'_>, ExpressionError> {
+    let mut cells = Vec::new();
+    let parts = split_list(text);
+    for (part_offset, part) in &parts {
+        let start = offset + part_offset + (part.len() - part.trim_start().len());
+        let part = part.trim();
+        let (value, count) = match part.rsplit_once(char::is_whitespace) {
+            Some((value, count)) if count.strip_prefix('x').is_some_and(is_number) => {
+                let count_start = start + part.len() - count.len();
+                let count = match count[1..].parse::<usize>() {
+                    Ok(count) if (1..=100).contains(&count) => count,
+                    _ => {
+                        return Err(ExpressionError::InvalidCount(
+                            count_start,
+                            start + part.len(),
+                        ))
+                    }
+                };
+                (value.trim_end(), count)
+            }
+            _ => (part, 1),
+        };
+        if parts.len() == 1 && count == 1 {
+            return if value.starts_with('"') {
+                parse_text(value, start).map(RightField::Text)
+            } else {
+                parse_operand(value, start)
+            };
+        }
+        let values = if value.starts_with('"') {
+            parse_text(value, start)?
+                .into_iter()
+                .map(|code| LinePart {
+                    start: code.start,
+                    end: code.end,
+                    value: RightField::Literal(code.value),
+                })
+                .collect()
+        } else {
+            vec![LinePart {
+                start,
+                end: start + value.len(),
+                value: parse_operand(value, start)?,
+            }]
+        };
+        for _ in 0..count {
+            cells.extend(values.iter().cloned());
+        }
+    }
+    Ok(RightField::List(cells))
+}
+
+fn tokenize(text: &str, offset: usize) -> Result<Vec<(Token<'_>, usize, usize)>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut atom_start = None;
+    let mut chars = text.char_indices();
+    while let Some((index, char)) = chars.next() {
+        if char == '\'' && atom_start.is_none() {
+            let (codes, length) = decode(&text[index + 1..], offset + index + 1, '\'')?;
+            let end = offset + index + 1 + length;
+            let [code] = codes.as_slice() else {
+                return Err(ExpressionError::Unencodable(offset + index, end));
+            };
+            tokens.push((Token::Char(code.value), offset + index, end));
+            // Skip the rest of the literal, which `decode` already read.
+            for _ in text[index + 1..index + 1 + length].chars() {
+                chars.next();
+            }
+            continue;
+        }
+        let token = match char {
+            '+' => Some(Token::Plus),
+            '-' => Some(Token::Minus),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            _ => None,
+        };
+        if token.is_some() || char.is_whitespace() {
+            if let Some(start) = atom_start.take() {
+                tokens.push((
+                    Token::Atom(&text[start..index]),
+                    offset + start,
+                    offset + index,
+                ));
+            }
+        } else if atom_start.is_none() {
+            atom_start = Some(index);
+        }
+        if let Some(token) = token {
+            tokens.push((token, offset + index, offset + index + 1));
+        }
+    }
+    if let Some(start) = atom_start {
+        tokens.push((
+            Token::Atom(&text[start..]),
+            offset + start,
+            offset + text.len(),
+        ));
+    }
+    Ok(tokens)
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|char| char.is_ascii_digit())
+}
+
+/// Parses a single number, label or `&` address spanning `start..end`.
+fn parse_atom(atom: &str, start: usize, end: usize) -> Result<RightField<'_>, ExpressionError> {
+    // Numbers too long for a u16 are still numbers, so they are checked by length first.
+    let value = |digits: &str| {
+        let digits = digits.trim_start_matches('0');
+        if digits.len() > 3 {
+            None
+        } else {
+            Some(digits.parse::<u16>().unwrap_or(0))
+        }
+    };
+    if atom.starts_with(['"', '\'']) {
+        Err(ExpressionError::Invalid(start, end))
+    } else if let Some(address) = atom.strip_prefix("&") {
+        if !is_number(address) {
+            return Err(ExpressionError::InvalidAddress(start, end));
+        }
+        match value(address) {
+            Some(address) if address <= 99 => Ok(RightField::Address(address)),
+            _ => Err(ExpressionError::AddressOutOfRange(start, end)),
+        }
+    } else if is_number(atom) {
+        value(atom)
+            .map(RightField::Literal)
+            .ok_or(ExpressionError::LiteralOutOfRange(start, end))
+    } else if is_reference(atom) {
+        Ok(RightField::Label(Cow::Borrowed(atom)))
+    } else {
+        Err(ExpressionError::Invalid(start, end))
+    }
+}
+
+/// Most parentheses and negations an operand may nest, which keeps the recursive parser and
+/// evaluator from overflowing the stack.
+const MAX_NESTING: usize = 32;
+/// Most numbers, labels and characters in one operand.
+const MAX_TERMS: usize = 64;
+
+struct Parser<'a> {
+    tokens: Vec<(Token<'a>, usize, usize)>,
+    position: usize,
+    end: usize,
+    nesting: usize,
+    terms: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(Token<'a>, usize, usize)> {
+        self.tokens.get(self.position).copied()
+    }
+    fn unexpected(&self) -> ExpressionError {
+        match self.peek() {
+            Some((_, start, end)) => ExpressionError::Invalid(start, end),
+            None => ExpressionError::Invalid(self.end, self.end + 1),
+        }
+    }
+    fn expression(&mut self) -> Result<Expression<'a>, ExpressionError> {
+        let mut left = self.term()?;
+        while let Some((token @ (Token::Plus | Token::Minus), _, _)) = self.peek() {
+            self.position += 1;
+            let right = Box::new(self.term()?);
+            left = match token {
+                Token::Plus => Expression::Add(Box::new(left), right),
+                _ => Expression::Subtract(Box::new(left), right),
+            };
+        }
+        Ok(left)
+    }
+    fn term(&mut self) -> Result<Expression<'a>, ExpressionError> {
+        let Some((token, start, end)) = self.peek() else {
+            return Err(self.unexpected());
+        };
+        let too_deep = matches!(token, Token::Minus | Token::Open) && self.nesting == MAX_NESTING;
+        let too_long = matches!(token, Token::Char(_) | Token::Atom(_)) && self.terms == MAX_TERMS;
+        if too_deep || too_long {
+            return Err(ExpressionError::Invalid(start, end));
+        }
+        match token {
+            Token::Minus => {
+                self.position += 1;
+                self.nesting += 1;
+                let inner = self.term()?;
+                self.nesting -= 1;
+                Ok(Expression::Negate(Box::new(inner)))
+            }
+            Token::Open => {
+                self.position += 1;
+                self.nesting += 1;
+                let inner = self.expression()?;
+                self.nesting -= 1;
+                match self.peek() {
+                    Some((Token::Close, _, _)) => {
+                        self.position += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(self.unexpected()),
+                }
+            }
+            Token::Char(code) => {
+                self.position += 1;
+                self.terms += 1;
+                Ok(Expression::Operand(LinePart {
+                    start,
+                    end,
+                    value: RightField::Literal(code),
+                }))
+            }
+            Token::Atom(atom) => {
+                let value = parse_atom(atom, start, end)?;
+                self.position += 1;
+                self.terms += 1;
+                Ok(Expression::Operand(LinePart { start, end, value }))
+            }
+            Token::Plus | Token::Close => Err(self.unexpected()),
+        }
+    }
+}
+
+/// Parses the operand `text`, which starts at column `offset` of its line. Plain numbers,
+/// labels and addresses are returned as they are, anything else as an expression.
+pub fn parse_operand(text: &str, offset: usize) -> Result<RightField<'_>, ExpressionError> {
+    let mut parser = Parser {
+        tokens: tokenize(text, offset)?,
+        position: 0,
+        end: offset + text.len(),
+        nesting: 0,
+        terms: 0,
+    };
+    let expression = parser.expression()?;
+    if let Some((_, start, _)) = parser.peek() {
+        return Err(ExpressionError::Trailing(start, parser.end));
+    }
+    Ok(match expression {
+        Expression::Operand(operand) => operand.value,
+        expression => RightField::Expression(Box::new(expression)),
+    })
+}
+
+impl<'a> Expression<'a> {
+    /// Computes the value of the expression, looking every operand up with `resolve`.
+    pub fn evaluate<E>(
+        &self,
+        resolve: &mut impl FnMut(&LinePart<RightField<'a>>) -> Result<i32, E>,
+    ) -> Result<i32, E> {
+        match self {
+            Expression::Operand(operand) => resolve(operand),
+            Expression::Negate(inner) => Ok(-inner.evaluate(resolve)?),
+            Expression::Add(left, right) => Ok(left.evaluate(resolve)? + right.evaluate(resolve)?),
+            Expression::Subtract(left, right) => {
+                Ok(left.evaluate(resolve)? - right.evaluate(resolve)?)
+            }
+        }
+    }
+    /// Calls `f` on every operand of the expression.
+    pub fn for_each_operand<'b>(&'b self, f: &mut impl FnMut(&'b LinePart<RightField<'a>>)) {
+        match self {
+            Expression::Operand(operand) => f(operand),
+            Expression::Negate(inner) => inner.for_each_operand(f),
+            Expression::Add(left, right) | Expression::Subtract(left, right) => {
+                left.for_each_operand(f);
+                right.for_each_operand(f);
+            }
+        }
+    }
+    /// Calls `f` on every operand of the expression.
+    pub fn for_each_operand_mut(&mut self, f: &mut impl FnMut(&mut LinePart<RightField<'a>>)) {
+        match self {
+            Expression::Operand(operand) => f(operand),
+            Expression::Negate(inner) => inner.for_each_operand_mut(f),
+            Expression::Add(left, right) | Expression::Subtract(left, right) => {
+                left.for_each_operand_mut(f);
+                right.for_each_operand_mut(f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    /// The value of the operand `text`, with the label `X` at 10.
+    fn value(text: &str) -> i32 {
+        let mut resolve = |operand: &LinePart<RightField>| match &operand.value {
+            RightField::Literal(value) => Ok(*value as i32),
+            RightField::Label(label) if label == "X" => Ok(10),
+            other => Err(format!("unexpected operand {other:?}")),
+        };
+        let operand = parse_operand(text, 0).unwrap();
+        let result = match operand {
+            RightField::Expression(expression) => expression.evaluate(&mut resolve),
+            value => resolve(&LinePart {
+                start: 0,
+                end: text.len(),
+                value,
+            }),
+        };
+        result.unwrap()
+    }
+
+    #[test]
+    fn evaluates_sums_differences_and_parentheses() {
+        assert_eq!(value("X+1"), 11);
+        assert_eq!(value("X - 2"), 8);
+        assert_eq!(value("(8-2)-(1+1)"), 4);
+        assert_eq!(value("-3+5"), 2);
+        assert_eq!(value("X-(X-'A')"), 65);
+    }
+
+    #[test]
+    fn plain_operands_are_not_expressions() {
+        assert_eq!(parse_operand("42", 0), Ok(RightField::Literal(42)));
+        assert_eq!(parse_operand("&7", 0), Ok(RightField::Address(7)));
+        assert_eq!(
+            parse_operand("X", 0),
+            Ok(RightField::Label(Cow::Borrowed("X")))
+        );
+    }
+
+    #[test]
+    fn reports_the_columns_of_errors() {
+        assert_eq!(
+            parse_operand("1 2", 4),
+            Err(ExpressionError::Trailing(6, 7))
+        );
+        assert!(matches!(
+            parse_operand("1+", 0),
+            Err(ExpressionError::Invalid(..))
+        ));
+        assert!(matches!(
+            parse_operand("(1", 0),
+            Err(ExpressionError::Invalid(..))
+        ));
+        assert_eq!(
+            parse_operand("X+1000", 0),
+            Err(ExpressionError::LiteralOutOfRange(2, 6))
+        );
+    }
+}
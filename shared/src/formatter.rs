@@ -0,0 +1,147 @@
+use crate::lexer::{is_reserved, split_columns, split_comment, LinePart};
+use std::collections::HashSet;
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// Narrowest label column, so lines without a label are always indented.
+const MIN_LABEL_WIDTH: usize = 4;
+
+/// One source line split into its columns.
+struct SourceLine<'a> {
+    label: Option<LinePart<&'a str>>,
+    /// Mnemonic, directive or macro name, with mnemonics and directives in upper case.
+    operation: Option<LinePart<String>>,
+    /// Everything between the operation and the comment.
+    operand: Option<LinePart<&'a str>>,
+    comment: Option<&'a str>,
+    indented: bool,
+}
+impl SourceLine<'_> {
+    fn is_code(&self) -> bool {
+        self.label.is_some() || self.operation.is_some()
+    }
+}
+
+/// Upper cases `word`, found in operation position, when it is a mnemonic or directive
+/// written in lower case. Macro names are kept as written.
+fn normalize(word: &str, macros: &HashSet<String>) -> String {
+    let upper = word.to_ascii_uppercase();
+    if is_reserved(&upper) && !macros.contains(word) {
+        upper
+    } else {
+        word.to_string()
+    }
+}
+
+/// Splits `line` into columns with the lexer's rules. `macros` holds the macros defined so
+/// far, which is how a macro call is told apart from a label. Like the lexer, a lower case
+/// mnemonic at the start of a line is a label, so `out DAT 1` keeps its label.
+fn split_line<'a>(line: &'a str, macros: &mut HashSet<String>) -> SourceLine<'a> {
+    let columns = split_columns(line, |word| is_reserved(word) || macros.contains(word));
+    let operation = columns.operation.map(|operation| LinePart {
+        start: operation.start,
+        end: operation.end,
+        value: normalize(operation.value, macros),
+    });
+    if let (Some(operation), Some(operand)) = (&operation, &columns.operand) {
+        if operation.value == "MACRO" {
+            if let Some(name) = operand.value.split_whitespace().next() {
+                macros.insert(name.to_string());
+            }
+        }
+    }
+    SourceLine {
+        label: columns.label,
+        operation,
+        operand: columns.operand,
+        comment: split_comment(line).1,
+        indented: line.starts_with(char::is_whitespace),
+    }
+}
+
+/// Lays `source` out in columns: labels, then mnemonics, then operands, then `//` comments,
+/// each column as wide as its longest entry. Mnemonics and directives are written in upper
+/// case. Comments and blank lines are kept, and indented full-line comments are moved to the
+/// mnemonic column.
+pub fn format(source: &str) -> String {
+    let mut macros = HashSet::new();
+    let lines: Vec<SourceLine> = source
+        .lines()
+        .map(|line| split_line(line, &mut macros))
+        .collect();
+    let width = |column: &dyn Fn(&SourceLine) -> Option<usize>| {
+        lines
+            .iter()
+            .filter_map(column)
+            .max()
+            .map_or(0, |width| width + 1)
+    };
+    let label_width = width(&|line| line.label.as_ref().map(|label| label.value.chars().count()))
+        .max(MIN_LABEL_WIDTH);
+    let operation_width = width(&|line| {
+        line.operation
+            .as_ref()
+            .map(|operation| operation.value.chars().count())
+    });
+    let operand_width = width(&|line| {
+        line.operand
+            .as_ref()
+            .map(|operand| operand.value.chars().count())
+    });
+
+    let mut output = String::new();
+    for line in &lines {
+        let comment = line
+            .comment
+            .map(|comment| format!("//{}", comment.trim_end()));
+        let formatted = if line.is_code() {
+            format!(
+                "{:<label_width$}{:<operation_width$}{:<operand_width$}{}",
+                line.label.as_ref().map_or("", |label| label.value),
+                line.operation
+                    .as_ref()
+                    .map_or("", |operation| operation.value.as_str()),
+                line.operand.as_ref().map_or("", |operand| operand.value),
+                comment.unwrap_or_default(),
+            )
+        } else if let Some(comment) = comment {
+            let indent = if line.indented { label_width } else { 0 };
+            format!("{:indent$}{}", "", comment)
+        } else {
+            String::new()
+        };
+        output.push_str(formatted.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+
+    #[test]
+    fn keeps_labels_spelled_like_mnemonics() {
+        let source = "LDA out\n  OUT\n  hlt\nout DAT 1\nx\tlda out";
+        let formatted = format(source);
+        assert_eq!(
+            formatted,
+            "    LDA out\n    OUT\nhlt\nout DAT 1\nx   LDA out\n"
+        );
+    }
+
+    #[test]
+    fn formatted_program_assembles_the_same() {
+        let source = "LDA out\nOUT\n  HLT // done\nout DAT 1\ndat DAT 'A'";
+        let formatted = format(source);
+        let (before, after) = (Sources::new(), Sources::new());
+        let before = assemble(&before, source).unwrap().mailbox;
+        let after = assemble(&after, &formatted).unwrap().mailbox;
+        for address in 0..100usize {
+            assert_eq!(before[address], after[address], "{formatted}");
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Errors and warnings as JSON, one object per line, for editors and scripts.
+//!
+//! Every object has these fields:
+//!
+//! - `severity`: `"error"` or `"warning"`
+//! - `code`: a stable code such as `"E0003"`, and `name`: the kind, such as `"UnsetLabel"`
+//! - `message`: the first line of the text output
+//! - `file`: `null` for an unnamed program, and `line`, counting from 1
+//! - `start` and `end`: the columns the caret points at, in characters counting from 0 with
+//!   `end` excluded
+//! - `hint`: a suggestion such as `"Did you mean LDA?"`, or `null`
+//! - `expansion`: for lines from a macro, the `macro` name and the location of its
+//!   invocation, otherwise `null`
+//! - `related`: for errors pointing at a second line, a `note` and that location, otherwise
+//!   `null`
+//!
+//! Errors reading or linking objects have no line: `file` names the object, or is `null` for
+//! errors about the whole program, and the other location fields are `null`.
+use crate::error::{AssemblerError, AssemblerWarning, ErrorInfo, Severity};
+use crate::object::{LinkError, ObjectError};
+use std::fmt::{Display, Formatter, Result};
+use std::string::{String, ToString};
+
+/// An error or warning written as a single line of JSON.
+pub struct JsonDiagnostic<'a> {
+    severity: Severity,
+    code: &'static str,
+    name: &'static str,
+    message: String,
+    location: Location<'a>,
+    related: Option<(&'static str, &'a ErrorInfo<'a>)>,
+}
+
+/// What a diagnostic points at.
+enum Location<'a> {
+    Source(&'a ErrorInfo<'a>),
+    /// An object, named when the error is about one.
+    Object(Option<&'a str>),
+}
+impl<'a> From<&'a AssemblerError<'a>> for JsonDiagnostic<'a> {
+    fn from(error: &'a AssemblerError<'a>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: error.code(),
+            name: error.name(),
+            message: error.message(),
+            location: Location::Source(error.info()),
+            related: error.related(),
+        }
+    }
+}
+impl<'a> From<&'a AssemblerWarning<'a>> for JsonDiagnostic<'a> {
+    fn from(warning: &'a AssemblerWarning<'a>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code: warning.kind().code(),
+            name: warning.kind().name(),
+            message: warning.message(),
+            location: Location::Source(warning.info()),
+            related: None,
+        }
+    }
+}
+impl<'a> From<&'a LinkError> for JsonDiagnostic<'a> {
+    fn from(error: &'a LinkError) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: error.code(),
+            name: error.name(),
+            message: error.to_string(),
+            location: Location::Object(error.object()),
+            related: None,
+        }
+    }
+}
+impl<'a> JsonDiagnostic<'a> {
+    /// `error` met while reading the object `file`.
+    pub fn object_error(error: &ObjectError, file: &'a str) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: error.code(),
+            name: error.name(),
+            message: error.to_string(),
+            location: Location::Object(Some(file)),
+            related: None,
+        }
+    }
+}
+
+fn write_string(f: &mut Formatter<'_>, text: &str) -> Result {
+    write!(f, "\"")?;
+    for char in text.chars() {
+        match char {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            char if char.is_control() => write!(f, "\\u{:04x}", char as u32)?,
+            char => write!(f, "{}", char)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Writes the location fields of `info`, without the surrounding braces.
+fn write_location(f: &mut Formatter<'_>, info: &ErrorInfo) -> Result {
+    write!(f, "\"file\":")?;
+    match info.file() {
+        Some(file) => write_string(f, file)?,
+        None => write!(f, "null")?,
+    }
+    let (start, end) = info.columns();
+    write!(
+        f,
+        ",\"line\":{},\"start\":{},\"end\":{},\"hint\":",
+        info.line + 1,
+        start,
+        end
+    )?;
+    match &info.hint {
+        Some(hint) => write_string(f, &hint.to_string())?,
+        None => write!(f, "null")?,
+    }
+    write!(f, ",\"expansion\":")?;
+    match &info.expansion {
+        Some(expansion) => {
+            write!(f, "{{\"macro\":")?;
+            write_string(f, expansion.macro_name)?;
+            write!(f, ",")?;
+            write_location(f, &expansion.invocation)?;
+            write!(f, "}}")
+        }
+        None => write!(f, "null"),
+    }
+}
+
+impl Display for JsonDiagnostic<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{{\"severity\":\"{}\",\"code\":\"{}\",\"name\":\"{}\",\"message\":",
+            self.severity.name(),
+            self.code,
+            self.name
+        )?;
+        write_string(f, &self.message)?;
+        write!(f, ",")?;
+        match self.location {
+            Location::Source(info) => write_location(f, info)?,
+            Location::Object(file) => {
+                write!(f, "\"file\":")?;
+                match file {
+                    Some(file) => write_string(f, file)?,
+                    None => write!(f, "null")?,
+                }
+                write!(
+                    f,
+                    ",\"line\":null,\"start\":null,\"end\":null,\"hint\":null,\"expansion\":null"
+                )?;
+            }
+        }
+        write!(f, ",\"related\":")?;
+        match self.related {
+            Some((note, related)) => {
+                write!(f, "{{\"note\":")?;
+                write_string(f, note)?;
+                write!(f, ",")?;
+                write_location(f, related)?;
+                write!(f, "}}")?;
+            }
+            None => write!(f, "null")?,
+        }
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+    use std::vec::Vec;
+
+    /// The JSON lines of the errors, or else the warnings, of `source`.
+    fn json(source: &str) -> Vec<String> {
+        let sources = Sources::new();
+        match assemble(&sources, source) {
+            Ok(program) => program
+                .warnings
+                .iter()
+                .map(|warning| JsonDiagnostic::from(warning).to_string())
+                .collect(),
+            Err(diagnostics) => diagnostics
+                .errors
+                .iter()
+                .map(|error| JsonDiagnostic::from(error).to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn writes_errors_with_code_location_and_hint() {
+        assert_eq!(
+            json(" LDA COUNTT\nCOUNT DAT\n HLT"),
+            [concat!(
+                r#"{"severity":"error","code":"E0003","name":"UnsetLabel","#,
+                r#""message":"Unset label COUNTT at line 1","file":null,"line":1,"#,
+                r#""start":5,"end":11,"hint":"Did you mean COUNT?","expansion":null,"#,
+                r#""related":null}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        let errors = json("Aé LDA X\n LDA Bé\nX DAT");
+        assert!(errors[0].contains(r#""line":1,"start":0,"end":2,"#));
+        assert!(errors[1].contains(r#""line":2,"start":5,"end":7,"#));
+    }
+
+    #[test]
+    fn writes_warnings_and_expansions() {
+        let warnings = json("HLT\nX DAT");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with(r#"{"severity":"warning","code":"W0001""#));
+        let errors = json("MACRO M\n LDA NOPE\nENDM\n HLT\n M");
+        assert!(errors[0].contains(r#""line":2,"#));
+        assert!(errors[0].contains(r#""expansion":{"macro":"M","file":null,"line":5,"#));
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let errors = json("a\"b\\c HLT");
+        assert!(errors[0].contains(r#""message":"Invalid label a\"b\\c at line 1"#));
+    }
+
+    #[test]
+    fn writes_link_errors_without_lines() {
+        let error = LinkError::UndefinedSymbol("X".to_string(), "lib.txt".to_string());
+        let json = JsonDiagnostic::from(&error).to_string();
+        assert!(json.contains(r#""code":"L0001","name":"UndefinedSymbol","#));
+        assert!(json.contains(r#""file":"lib.txt","line":null,"start":null,"end":null,"#));
+        let json = JsonDiagnostic::from(&LinkError::ProgramTooLarge(120)).to_string();
+        assert!(json.contains(r#""file":null,"line":null,"#));
+        let json = JsonDiagnostic::object_error(&ObjectError::MissingHeader, "a.obj").to_string();
+        assert!(json.contains(r#""code":"O0002","#));
+        assert!(json.contains(r#""file":"a.obj","#));
+    }
+}
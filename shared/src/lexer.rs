@@ -1,26 +1,79 @@
 use crate::error::AssemblerError::{EndOfLineExpected, UnexpectedInstruction};
-use crate::error::{AssemblerError, ErrorInfo};
-use crate::MemonicType;
-use std::collections::HashMap;
-use std::io::{BufRead, Lines};
-use std::iter::Enumerate;
+use crate::error::{AssemblerError, ErrorInfo, Hint};
+use crate::expression::{parse_data, parse_operand, Expression, ExpressionError};
+use crate::pseudo::{Operand, PseudoOp};
+use crate::{MemonicType, OpCode};
+use std::borrow::Cow;
+use std::boxed::Box;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::format;
+use std::fs;
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::string::{String, ToString};
+use std::vec;
+use std::vec::Vec;
 
 pub type LabelLookup = HashMap<String, u16>;
-pub type LexerResult = [Option<LineStructure>; 100];
-#[derive(Debug, PartialEq)]
-pub enum RightField {
+pub type LexerResult<'a> = [Option<LineStructure<'a>>; 100];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RightField<'a> {
     Literal(u16),
-    Label(String),
+    /// A name, borrowed from the source unless the lexer made it up, such as `MAIN.loop`
+    /// for `.loop` or the label of a pool cell.
+    Label(Cow<'a, str>),
     Address(u16),
+    Expression(Box<Expression<'a>>),
+    /// Character codes of a `DAT "..."` string, one cell each.
+    Text(Vec<LinePart<u16>>),
+    /// `=value`, the address of a cell of the constant pool holding `value`. The lexer
+    /// replaces it with the label of that cell, see [`is_pool_label`]. Instructions may only
+    /// read it, since other instructions share the cell.
+    Constant(Box<LinePart<RightField<'a>>>),
+    /// Values of a `DAT` filling several cells, such as `DAT 1, 2, 3`, `DAT 0 x10` or
+    /// `DS 10`, one per cell.
+    List(Vec<LinePart<RightField<'a>>>),
+}
+impl RightField<'_> {
+    /// Every label the field refers to.
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            RightField::Label(label) => vec![label.as_ref()],
+            RightField::Expression(expression) => {
+                let mut labels = Vec::new();
+                expression.for_each_operand(&mut |operand| labels.extend(operand.value.labels()));
+                labels
+            }
+            RightField::Constant(value) => value.value.labels(),
+            RightField::List(values) => values
+                .iter()
+                .flat_map(|value| value.value.labels())
+                .collect(),
+            RightField::Literal(_) | RightField::Address(_) | RightField::Text(_) => Vec::new(),
+        }
+    }
+    /// Moves every column of the field to `start..end`, used when a macro argument is
+    /// substituted into a line other than the one it was written on.
+    fn with_span(mut self, start: usize, end: usize) -> Self {
+        if let RightField::Expression(expression) = &mut self {
+            expression.for_each_operand_mut(&mut |operand| {
+                operand.start = start;
+                operand.end = end;
+            });
+        }
+        self
+    }
 }
 #[derive(Debug)]
-pub enum LexerState {
-    Some(LineStructure),
-    Err(AssemblerError),
+pub enum LexerState<'a> {
+    Some(LineStructure<'a>),
+    Err(AssemblerError<'a>),
     Skip,
 }
-impl LexerState {
+impl LexerState<'_> {
     pub fn is_some(&self) -> bool {
         matches!(self, LexerState::Some(_))
     }
@@ -31,10 +84,10 @@ impl LexerState {
         matches!(self, LexerState::Skip)
     }
 }
-impl<V: FromIterator<Option<LineStructure>>> FromIterator<LexerState>
-    for Result<V, AssemblerError>
+impl<'a, V: FromIterator<Option<LineStructure<'a>>>> FromIterator<LexerState<'a>>
+    for Result<V, AssemblerError<'a>>
 {
-    fn from_iter<T: IntoIterator<Item = LexerState>>(iter: T) -> Result<V, AssemblerError> {
+    fn from_iter<T: IntoIterator<Item = LexerState<'a>>>(iter: T) -> Result<V, AssemblerError<'a>> {
         iter.into_iter()
             .filter(|x| !x.is_skip())
             .map(|i| match i {
@@ -46,160 +99,1923 @@ impl<V: FromIterator<Option<LineStructure>>> FromIterator<LexerState>
     }
 }
 
+/// Every file of a program. Lines, tokens and errors borrow from it instead of copying
+/// text, and files are only ever added, so they stay valid as long as it.
+#[derive(Default)]
+pub struct Sources<'a> {
+    first: OnceCell<Box<SourceNode<'a>>>,
+}
+struct SourceNode<'a> {
+    file: SourceFile<'a>,
+    next: OnceCell<Box<SourceNode<'a>>>,
+}
+impl<'a> Sources<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Stores `file` after the files added so far.
+    fn add(&'a self, file: SourceFile<'a>) -> &'a SourceFile<'a> {
+        let mut cell = &self.first;
+        while let Some(node) = cell.get() {
+            cell = &node.next;
+        }
+        let node = cell.get_or_init(|| {
+            Box::new(SourceNode {
+                file,
+                next: OnceCell::new(),
+            })
+        });
+        &node.file
+    }
+}
+
+/// A file of the program. Lines and errors point into its text instead of copying it, so
+/// every file is read exactly once.
 #[derive(Debug)]
+pub struct SourceFile<'a> {
+    /// `None` for an unnamed program.
+    pub name: Option<String>,
+    /// Borrowed for the program, read from disk for included files.
+    pub text: Cow<'a, str>,
+    /// File and line of the `INCLUDE` that first read this file, `None` for the main file.
+    pub included_at: Option<(&'a SourceFile<'a>, u16)>,
+}
+impl SourceFile<'_> {
+    /// Lines of the `INCLUDE`s leading from the main file to `line` of this file, followed
+    /// by `line`. Comparing these orders lines of different files as they are read.
+    pub fn position(&self, line: u16) -> Vec<u16> {
+        let mut position = vec![line];
+        let mut parent = self.included_at.as_ref();
+        while let Some((file, line)) = parent {
+            position.push(*line);
+            parent = file.included_at.as_ref();
+        }
+        position.reverse();
+        position
+    }
+}
+
+/// One line of a [`SourceFile`].
+#[derive(Debug, Clone)]
+pub struct SourceLine<'a> {
+    pub file: &'a SourceFile<'a>,
+    /// Byte range of the line in the file, without the line break.
+    pub range: Range<usize>,
+}
+impl<'a> SourceLine<'a> {
+    pub fn literal(&self) -> &'a str {
+        &self.file.text[self.range.clone()]
+    }
+    pub fn file_name(&self) -> Option<&'a str> {
+        self.file.name.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct LinePart<T> {
     pub start: usize,
     pub end: usize,
     pub value: T,
 }
 
-#[derive(Debug)]
-pub struct LineStructure {
-    pub left: Option<LinePart<String>>,
+/// The source line a macro was invoked from, kept on every line of its expansion.
+#[derive(Debug, Clone)]
+pub struct Invocation<'a> {
+    pub name: &'a str,
+    pub line: u16,
+    pub start: usize,
+    pub end: usize,
+    pub source: SourceLine<'a>,
+}
+impl<'a> Invocation<'a> {
+    pub fn error_info(&self) -> ErrorInfo<'a> {
+        ErrorInfo {
+            start: self.start,
+            end: self.end,
+            line: self.line,
+            source: self.source.clone(),
+            expansion: None,
+            hint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LineStructure<'a> {
+    pub left: Option<LinePart<Cow<'a, str>>>,
     pub instruction: Option<LinePart<MemonicType>>,
-    pub right: Option<LinePart<RightField>>,
+    pub right: Option<LinePart<RightField<'a>>>,
     pub line: u16,
+    /// Mailbox address the line is assembled into.
+    pub address: u16,
+    pub source: SourceLine<'a>,
+    /// Set when the line comes from the body of a macro.
+    pub invocation: Option<Invocation<'a>>,
+    /// Set on a pseudo-instruction, which the lexer expands into real instructions.
+    pub pseudo: Option<LinePart<PseudoOp>>,
 }
 
-impl LineStructure {
-    fn new(line: u16) -> Self {
+impl<'a> LineStructure<'a> {
+    fn new(line: u16, source: SourceLine<'a>) -> Self {
         Self {
             left: None,
             instruction: None,
             right: None,
             line,
+            address: 0,
+            source,
+            invocation: None,
+            pseudo: None,
         }
     }
+    pub fn literal(&self) -> &'a str {
+        self.source.literal()
+    }
+    /// File the line was read from, `None` for an unnamed program.
+    pub fn file(&self) -> Option<&'a str> {
+        self.source.file_name()
+    }
+    /// Column range covering every part of the line.
+    pub fn span(&self) -> (usize, usize) {
+        let parts = [
+            self.left.as_ref().map(|part| (part.start, part.end)),
+            self.instruction.as_ref().map(|part| (part.start, part.end)),
+            self.right.as_ref().map(|part| (part.start, part.end)),
+        ];
+        parts
+            .into_iter()
+            .flatten()
+            .fold((usize::MAX, 0), |(start, end), (part_start, part_end)| {
+                (start.min(part_start), end.max(part_end))
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MacroCall<'a> {
+    left: Option<LinePart<Cow<'a, str>>>,
+    name: LinePart<&'a str>,
+    args: Vec<LinePart<RightField<'a>>>,
+    line: u16,
+    source: SourceLine<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirectiveKind {
+    /// Moves the location counter to the operand.
+    Org,
+    /// Names the operand without using a cell.
+    Equ,
+    /// Declares a label defined by another object, see [`crate::object`].
+    Import,
+    /// Makes a label available to other objects.
+    Export,
+}
+
+impl DirectiveKind {
+    fn from_string(word: &str) -> Option<Self> {
+        match word {
+            "ORG" => Some(DirectiveKind::Org),
+            "EQU" => Some(DirectiveKind::Equ),
+            "IMPORT" => Some(DirectiveKind::Import),
+            "EXPORT" => Some(DirectiveKind::Export),
+            _ => None,
+        }
+    }
+}
+
+/// A directive taking one operand, which is resolved while lexing.
+#[derive(Debug, Clone)]
+struct Directive<'a> {
+    kind: DirectiveKind,
+    left: Option<LinePart<Cow<'a, str>>>,
+    value: LinePart<RightField<'a>>,
+    error_info: ErrorInfo<'a>,
 }
 
+/// The test of an `IF`: `NAME` holds when `NAME` is defined and not 0, `NAME = value` and
+/// `NAME != value` compare its value as a number, or as text when either side is not one.
 #[derive(Debug, PartialEq)]
-pub enum TokenType {
-    Any,
-    LeftLabel,
-    RightLabel,
-    Instruction,
-    Eof,
+struct Condition<'a> {
+    name: &'a str,
+    comparison: Option<(bool, &'a str)>,
+}
+impl<'a> Condition<'a> {
+    /// Parses the text following `IF`.
+    fn parse(text: &'a str) -> Option<Self> {
+        let text = text.trim();
+        let (name, comparison) = match text.split_once('=') {
+            Some((name, value)) => match name.strip_suffix('!') {
+                Some(name) => (name.trim_end(), Some((false, value.trim_start()))),
+                None => (name.trim_end(), Some((true, value.trim_start()))),
+            },
+            None => (text, None),
+        };
+        let valid_value = |value: &str| {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || "_.-".contains(char))
+        };
+        (is_label(name) && comparison.is_none_or(|(_, value)| valid_value(value)))
+            .then_some(Self { name, comparison })
+    }
+    /// Whether the condition holds when `name` has the value `value`.
+    fn holds(&self, value: Option<&str>) -> bool {
+        let same = |a: &str, b: &str| match (a.parse::<i32>(), b.parse::<i32>()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => a == b,
+        };
+        match (self.comparison, value) {
+            (None, value) => value.is_some_and(|value| !same(value, "0")),
+            (Some((equal, expected)), Some(value)) => same(value, expected) == equal,
+            (Some((equal, _)), None) => !equal,
+        }
+    }
 }
 
-fn split_whitespace_with_index(s: &str) -> impl Iterator<Item = (&str, usize)> {
+/// An `IF` block being read.
+struct Conditional<'a> {
+    /// Whether the lines around the block are assembled.
+    enclosing: bool,
+    /// Whether the condition held.
+    holds: bool,
+    in_else: bool,
+    error_info: ErrorInfo<'a>,
+}
+impl Conditional<'_> {
+    /// Whether the lines of the current branch are assembled.
+    fn active(&self) -> bool {
+        self.enclosing && self.holds != self.in_else
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MacroLine<'a> {
+    Line(LineStructure<'a>),
+    Call(MacroCall<'a>),
+    Directive(Directive<'a>),
+}
+
+#[derive(Debug, Clone)]
+struct Macro<'a> {
+    name: &'a str,
+    params: Vec<&'a str>,
+    body: Vec<MacroLine<'a>>,
+    error_info: ErrorInfo<'a>,
+}
+
+enum LexedLine<'a> {
+    Skip,
+    /// An error, with the label of the line so it still gets defined.
+    Err(AssemblerError<'a>, Option<(Cow<'a, str>, ErrorInfo<'a>)>),
+    Line(LineStructure<'a>),
+    MacroStart(Macro<'a>),
+    MacroEnd(ErrorInfo<'a>),
+    MacroCall(MacroCall<'a>),
+    Include(&'a str, ErrorInfo<'a>),
+    Directive(Directive<'a>),
+}
+
+/// Whether `name` labels a cell of the constant pool. Such labels are named after the value
+/// of the cell, `=72`, or after its operand when that uses labels, `=TABLE+1`.
+pub fn is_pool_label(name: &str) -> bool {
+    name.starts_with('=')
+}
+
+/// Whether `name` labels the address following the expansion of a pseudo-instruction, like
+/// `@JNZ3`, which source labels cannot be named.
+pub fn is_generated_label(name: &str) -> bool {
+    name.starts_with('@')
+}
+
+pub(crate) fn split_whitespace_with_index(s: &str) -> impl Iterator<Item = (&str, usize)> {
     s.split_whitespace()
         .map(move |sub| (sub, sub.as_ptr() as usize - s.as_ptr() as usize))
 }
 
-pub struct Lexer<T: BufRead> {
+pub(crate) fn is_reserved(word: &str) -> bool {
+    MemonicType::from_string(word).is_some()
+        || PseudoOp::from_string(word).is_some()
+        || [
+            "MACRO", "ENDM", "INCLUDE", "ORG", "EQU", "IF", "ELSE", "ENDIF", "IMPORT", "EXPORT",
+            "DS",
+        ]
+        .contains(&word)
+}
+
+/// Whether `mnemonic` writes to or branches to its operand, which a pool constant shared by
+/// other instructions cannot be.
+fn is_destination(mnemonic: MemonicType) -> bool {
+    matches!(
+        mnemonic,
+        MemonicType::STA
+            | MemonicType::INP
+            | MemonicType::BRA
+            | MemonicType::BRZ
+            | MemonicType::BRP
+    )
+}
+
+/// Parses the operand of an instruction, where `=value` stands for a pool constant.
+fn parse_instruction_operand(
+    operand: &str,
+    start: usize,
+) -> Result<RightField<'_>, ExpressionError> {
+    match operand.strip_prefix('=') {
+        Some(constant) => parse_operand(constant, start + 1).map(|value| {
+            RightField::Constant(Box::new(LinePart {
+                start: start + 1,
+                end: start + operand.len(),
+                value,
+            }))
+        }),
+        None => parse_operand(operand, start),
+    }
+}
+
+/// Parses the operands of `MOV`, separated by commas or, without commas, by spaces.
+fn parse_operands(operands: &str, start: usize) -> Result<RightField<'_>, ExpressionError> {
+    let parts: Vec<(&str, usize)> = if operands.contains(',') {
+        let mut offset = 0;
+        operands
+            .split(',')
+            .map(|part| {
+                let index = offset + part.len() - part.trim_start().len();
+                offset += part.len() + 1;
+                (part.trim(), index)
+            })
+            .collect()
+    } else {
+        split_whitespace_with_index(operands).collect()
+    };
+    let values = parts
+        .into_iter()
+        .map(|(part, index)| {
+            if part.is_empty() {
+                return Err(ExpressionError::Invalid(start + index, start + index + 1));
+            }
+            Ok(LinePart {
+                start: start + index,
+                end: start + index + part.len(),
+                value: parse_instruction_operand(part, start + index)?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RightField::List(values))
+}
+
+fn is_identifier(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+/// A global label, or a local label such as `.loop`.
+fn is_label(word: &str) -> bool {
+    is_identifier(word.strip_prefix('.').unwrap_or(word))
+}
+
+/// A label an operand may refer to: a global label, a local label of the current scope such
+/// as `.loop`, or one of another scope such as `MAIN.loop`.
+pub(crate) fn is_reference(word: &str) -> bool {
+    match word.split_once('.') {
+        Some((scope, local)) => (scope.is_empty() || is_identifier(scope)) && is_identifier(local),
+        None => is_identifier(word),
+    }
+}
+
+/// Text of the operand starting at column `start`, up to the comment.
+fn operand_text(line_literal: &str, start: usize) -> &str {
+    split_comment(&line_literal[start..]).0.trim_end()
+}
+
+/// Splits `line` into its code and the text after `//`, ignoring `//` inside quotes.
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, char)) = chars.next() {
+        match (quote, char) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(open), char) if char == open => quote = None,
+            (None, '"' | '\'') => quote = Some(char),
+            (None, '/') if chars.peek().is_some_and(|(_, next)| *next == '/') => {
+                return (&line[..index], Some(&line[index + 2..]));
+            }
+            _ => {}
+        }
+    }
+    (line, None)
+}
+
+/// Label, operation and operand of a source line, each a span of its code.
+#[derive(Debug, Default)]
+pub(crate) struct Columns<'a> {
+    pub label: Option<LinePart<&'a str>>,
+    /// Mnemonic, pseudo-op, directive or macro name.
+    pub operation: Option<LinePart<&'a str>>,
+    /// Everything after the operation up to the comment.
+    pub operand: Option<LinePart<&'a str>>,
+}
+
+/// Splits `line` into columns the way the lexer reads it. A line starts with a label unless
+/// `is_operation` accepts its first word, and the word following a label is the operation.
+pub(crate) fn split_columns(line: &str, is_operation: impl Fn(&str) -> bool) -> Columns<'_> {
+    let code = split_comment(line).0;
+    let mut words = split_whitespace_with_index(code).map(|(word, index)| LinePart {
+        start: index,
+        end: index + word.len(),
+        value: word,
+    });
+    let Some(first) = words.next() else {
+        return Columns::default();
+    };
+    let (label, operation) = if is_operation(first.value) {
+        (None, Some(first))
+    } else {
+        (Some(first), words.next())
+    };
+    let operand = words.next().map(|word| {
+        let text = operand_text(line, word.start);
+        LinePart {
+            start: word.start,
+            end: word.start + text.len(),
+            value: text,
+        }
+    });
+    Columns {
+        label,
+        operation,
+        operand,
+    }
+}
+
+/// Text of a pool constant using labels, `field` being its operand with local labels
+/// qualified. Operands with the same text have the same value, so they share a cell.
+fn pool_name(field: &RightField) -> String {
+    fn expression_name(expression: &Expression) -> String {
+        match expression {
+            Expression::Operand(operand) => pool_name(&operand.value),
+            Expression::Negate(inner) => format!("-({})", expression_name(inner)),
+            Expression::Add(left, right) => {
+                format!("{}+{}", expression_name(left), expression_name(right))
+            }
+            Expression::Subtract(left, right) => {
+                format!("{}-({})", expression_name(left), expression_name(right))
+            }
+        }
+    }
+    match field {
+        RightField::Literal(value) => value.to_string(),
+        RightField::Address(address) => format!("&{}", address),
+        RightField::Label(label) => label.to_string(),
+        RightField::Expression(expression) => expression_name(expression),
+        RightField::Text(_) | RightField::Constant(_) | RightField::List(_) => {
+            unreachable!("constants are single operands")
+        }
+    }
+}
+
+/// Parses the quoted path following `INCLUDE`, `rest` being the text after the keyword.
+fn parse_include_path(rest: &str) -> Option<&str> {
+    let (path, after) = rest.trim_start().strip_prefix('"')?.split_once('"')?;
+    let after = after.trim_start();
+    (after.is_empty() || after.starts_with("//")).then_some(path)
+}
+
+/// Most lines a file may have, so every line number fits in a `u16` when counted from 1.
+const MAX_LINES: u16 = u16::MAX;
+
+/// A file being lexed, with the position of its next line.
+struct OpenFile<'a> {
+    source: &'a SourceFile<'a>,
+    canonical: Option<PathBuf>,
+    position: usize,
+    line: u16,
+    /// Byte range of the last line read.
+    previous: Range<usize>,
+}
+impl<'a> OpenFile<'a> {
+    fn new(source: &'a SourceFile<'a>, canonical: Option<PathBuf>) -> Self {
+        Self {
+            source,
+            canonical,
+            position: 0,
+            line: 0,
+            previous: 0..0,
+        }
+    }
+    fn next_line(&mut self) -> Option<(u16, SourceLine<'a>)> {
+        let text = &self.source.text;
+        if self.position >= text.len() || self.line == MAX_LINES {
+            return None;
+        }
+        let start = self.position;
+        let end = text[start..]
+            .find('\n')
+            .map_or(text.len(), |index| start + index);
+        self.position = end + 1;
+        let end = if text[start..end].ends_with('\r') {
+            end - 1
+        } else {
+            end
+        };
+        self.line += 1;
+        self.previous = start..end;
+        Some((
+            self.line - 1,
+            SourceLine {
+                file: self.source,
+                range: start..end,
+            },
+        ))
+    }
+    /// The last line read, if the file has lines after it that cannot be numbered.
+    fn overflow(&self) -> Option<(u16, SourceLine<'a>)> {
+        (self.line == MAX_LINES && self.position < self.source.text.len()).then(|| {
+            (
+                self.line - 1,
+                SourceLine {
+                    file: self.source,
+                    range: self.previous.clone(),
+                },
+            )
+        })
+    }
+}
+
+pub struct Lexer<'a> {
+    /// Holds the files read, which every line and name borrows from.
+    store: &'a Sources<'a>,
     label_lookup: LabelLookup,
-    lines: Enumerate<Lines<T>>,
-    assembly_line: usize,
+    constants: LabelLookup,
+    /// Where every label and constant was defined.
+    definitions: HashMap<String, ErrorInfo<'a>>,
+    /// Names used by `ORG` and `EQU` operands, which never reach the assembler.
+    references: HashSet<String>,
+    /// Names declared with `IMPORT`, in order.
+    imports: Vec<String>,
+    /// Names given to `EXPORT`, with where they were exported.
+    exports: Vec<(String, ErrorInfo<'a>)>,
+    /// The file being lexed, on top of the files that included it.
+    open: Vec<OpenFile<'a>>,
+    /// Every file read so far, the program first.
+    sources: Vec<&'a SourceFile<'a>>,
+    /// Included files by their canonical path.
+    loaded: HashMap<PathBuf, &'a SourceFile<'a>>,
+    location: usize,
+    /// One past the highest address used.
+    end: usize,
+    /// Addresses of the mailbox given a cell so far.
+    used: [bool; 100],
+    /// Cells of the constant pool with their label, placed once the source is read.
+    pool: Vec<(String, LineStructure<'a>)>,
+    /// The last global label, which local labels belong to.
+    scope: Option<Cow<'a, str>>,
+    /// Labels of `DAT` cells, which subroutines start with.
+    data_labels: HashSet<String>,
+    /// Labels given to `CALL`.
+    called: HashSet<String>,
+    /// The subroutine of every `RET`, checked once every `CALL` is known.
+    returns: Vec<(String, ErrorInfo<'a>)>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    defining: Option<Macro<'a>>,
+    pending: VecDeque<LexerState<'a>>,
+    expansions: usize,
+    /// Open `IF` blocks, innermost last.
+    conditionals: Vec<Conditional<'a>>,
+    /// Values given on the command line, for `IF` conditions.
+    defines: HashMap<String, String>,
 }
-impl<T: BufRead> Lexer<T> {
-    pub fn new(line: Lines<T>) -> Self {
+impl<'a> Lexer<'a> {
+    /// Creates a lexer for `source`. It and every file it includes are added to `store`.
+    pub fn new(store: &'a Sources<'a>, source: &'a str) -> Self {
+        Self::with_source(
+            store,
+            SourceFile {
+                name: None,
+                text: Cow::Borrowed(source),
+                included_at: None,
+            },
+            None,
+        )
+    }
+    /// Creates a lexer for the program stored in `file`, whose content is `source`.
+    /// `INCLUDE` paths are resolved relative to it.
+    pub fn with_file(store: &'a Sources<'a>, source: &'a str, file: &str) -> Self {
+        Self::with_source(
+            store,
+            SourceFile {
+                name: Some(file.to_string()),
+                text: Cow::Borrowed(source),
+                included_at: None,
+            },
+            fs::canonicalize(file).ok(),
+        )
+    }
+    fn with_source(
+        store: &'a Sources<'a>,
+        source: SourceFile<'a>,
+        canonical: Option<PathBuf>,
+    ) -> Self {
+        let source = store.add(source);
         Lexer {
+            store,
             label_lookup: Default::default(),
-            lines: line.enumerate(),
-            assembly_line: 0,
+            constants: Default::default(),
+            definitions: HashMap::new(),
+            references: HashSet::new(),
+            imports: Vec::new(),
+            exports: Vec::new(),
+            open: vec![OpenFile::new(source, canonical)],
+            sources: vec![source],
+            loaded: HashMap::new(),
+            location: 0,
+            end: 0,
+            used: [false; 100],
+            pool: Vec::new(),
+            scope: None,
+            data_labels: HashSet::new(),
+            called: HashSet::new(),
+            returns: Vec::new(),
+            macros: HashMap::new(),
+            defining: None,
+            pending: VecDeque::new(),
+            expansions: 0,
+            conditionals: Vec::new(),
+            defines: HashMap::new(),
         }
     }
+    /// Sets values for `IF` conditions, which take precedence over names defined in the
+    /// program.
+    pub fn with_defines(mut self, defines: HashMap<String, String>) -> Self {
+        self.defines = defines;
+        self
+    }
     pub fn get_label_lookup(&self) -> &LabelLookup {
         &self.label_lookup
     }
+    /// Names defined with `EQU`.
+    pub fn get_constants(&self) -> &LabelLookup {
+        &self.constants
+    }
+    /// Where every label and constant was defined.
+    pub fn get_definitions(&self) -> &HashMap<String, ErrorInfo<'a>> {
+        &self.definitions
+    }
+    /// Names used by directives rather than by instructions.
+    pub fn get_references(&self) -> &HashSet<String> {
+        &self.references
+    }
+    /// Names declared with `IMPORT`.
+    pub fn get_imports(&self) -> &[String] {
+        &self.imports
+    }
+    /// Names given to `EXPORT`, which may not be defined.
+    pub fn get_exports(&self) -> &[(String, ErrorInfo<'a>)] {
+        &self.exports
+    }
+    /// The program followed by every file pulled in with `INCLUDE`, in the order they were
+    /// first read.
+    pub fn sources(&self) -> &[&'a SourceFile<'a>] {
+        &self.sources
+    }
+    /// Handles `line` if it is `IF`, `ELSE` or `ENDIF`, or if it is inside a branch that is
+    /// not assembled. Returns whether it was handled. Conditions are evaluated as the source
+    /// is read, so an `IF` inside a macro is evaluated where the macro is defined.
+    fn conditional(&mut self, file_line: u16, source: &SourceLine<'a>) -> bool {
+        let literal = source.literal();
+        let code = split_comment(literal).0;
+        let Some((keyword, index)) = split_whitespace_with_index(code).next() else {
+            return !self.is_active();
+        };
+        if !["IF", "ELSE", "ENDIF"].contains(&keyword) {
+            return !self.is_active();
+        }
+        let info = |start: usize, end: usize| ErrorInfo {
+            start,
+            end,
+            line: file_line,
+            source: source.clone(),
+            expansion: None,
+            hint: None,
+        };
+        let error_info = info(index, index + keyword.len());
+        let rest = &code[index + keyword.len()..];
+        let enclosing = self.is_active();
+        let error = match (keyword, self.conditionals.last_mut()) {
+            ("IF", _) => {
+                let holds = match Condition::parse(rest) {
+                    Some(condition) => {
+                        condition.holds(self.symbol_value(condition.name).as_deref())
+                    }
+                    None if enclosing => {
+                        let info = info(index, code.trim_end().len());
+                        self.pending
+                            .push_back(LexerState::Err(AssemblerError::InvalidCondition(info)));
+                        false
+                    }
+                    // Lines that are not assembled are never reported.
+                    None => false,
+                };
+                self.conditionals.push(Conditional {
+                    enclosing,
+                    holds,
+                    in_else: false,
+                    error_info,
+                });
+                None
+            }
+            (_, None) => Some(AssemblerError::UnmatchedConditional(
+                error_info,
+                keyword.to_string(),
+            )),
+            ("ELSE", Some(conditional)) if conditional.in_else => conditional
+                .enclosing
+                .then_some(AssemblerError::DuplicateElse(error_info)),
+            ("ELSE", Some(conditional)) => {
+                conditional.in_else = true;
+                None
+            }
+            (_, Some(_)) => {
+                self.conditionals.pop();
+                None
+            }
+        };
+        if let Some(error) = error {
+            self.pending.push_back(LexerState::Err(error));
+        }
+        if !rest.trim().is_empty() && keyword != "IF" && enclosing {
+            let start = index + keyword.len() + (rest.len() - rest.trim_start().len());
+            let info = info(start, code.trim_end().len());
+            self.pending
+                .push_back(LexerState::Err(EndOfLineExpected(info)));
+        }
+        true
+    }
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(Conditional::active)
+    }
+    /// Value of `name` for an `IF` condition: the command line value, or else the value of
+    /// the constant or the address of the label defined so far.
+    fn symbol_value(&self, name: &'a str) -> Option<String> {
+        if let Some(value) = self.defines.get(name) {
+            return Some(value.clone());
+        }
+        let name = self.qualify(Cow::Borrowed(name))?;
+        self.constants
+            .get(name.as_ref())
+            .or_else(|| self.label_lookup.get(name.as_ref()))
+            .map(|value| value.to_string())
+    }
+    /// Mnemonics, pseudo-ops and macro names, suggested for misspelled instructions.
+    fn instruction_names(&self) -> impl Iterator<Item = &str> {
+        MemonicType::ALL
+            .iter()
+            .map(|mnemonic| mnemonic.name())
+            .chain(PseudoOp::ALL.iter().map(|op| op.name()))
+            .chain(self.macros.keys().copied())
+    }
+    fn current_file(&self) -> Option<&'a str> {
+        self.open.last()?.source.name.as_deref()
+    }
+    fn lex_line(&self, file_line: u16, source: &SourceLine<'a>) -> LexedLine<'a> {
+        let line_literal = source.literal();
+        let mut current = LineStructure::new(file_line, source.clone());
+        let trimmed = line_literal.trim();
+        if trimmed.starts_with("//") || trimmed.is_empty() {
+            return LexedLine::Skip;
+        }
+        let error_info = |start: usize, end: usize| ErrorInfo {
+            start,
+            end,
+            line: file_line,
+            source: source.clone(),
+            expansion: None,
+            hint: None,
+        };
+        let label = |current: &LineStructure<'a>| {
+            current
+                .left
+                .as_ref()
+                .map(|left| (left.value.clone(), error_info(left.start, left.end)))
+        };
+        let expression_error = |error: ExpressionError| match error {
+            ExpressionError::Invalid(start, end) => {
+                AssemblerError::InvalidExpression(error_info(start, end))
+            }
+            ExpressionError::Trailing(start, end) => EndOfLineExpected(error_info(start, end)),
+            ExpressionError::Unterminated(start, end) => {
+                AssemblerError::UnterminatedString(error_info(start, end))
+            }
+            ExpressionError::Unencodable(start, end) => AssemblerError::UnencodableCharacter(
+                error_info(start, end),
+                line_literal[start..end].to_string(),
+            ),
+            ExpressionError::InvalidAddress(start, end) => AssemblerError::InvalidAddress(
+                error_info(start, end),
+                line_literal[start..end].to_string(),
+            ),
+            ExpressionError::AddressOutOfRange(start, end) => AssemblerError::AddressOutOfRange(
+                error_info(start, end),
+                line_literal[start..end].to_string(),
+            ),
+            ExpressionError::LiteralOutOfRange(start, end) => AssemblerError::LiteralOutOfRange(
+                error_info(start, end),
+                line_literal[start..end].to_string(),
+            ),
+            ExpressionError::InvalidCount(start, end) => AssemblerError::InvalidCount(
+                error_info(start, end),
+                line_literal[start..end].to_string(),
+            ),
+        };
+        let code = split_comment(line_literal).0;
+        let columns = split_columns(line_literal, |word| {
+            is_reserved(word) || self.macros.contains_key(word)
+        });
+        if let Some(left) = &columns.label {
+            if !is_label(left.value) {
+                return LexedLine::Err(
+                    AssemblerError::InvalidLabel(
+                        error_info(left.start, left.end),
+                        left.value.to_string(),
+                    ),
+                    None,
+                );
+            }
+            current.left = Some(LinePart {
+                start: left.start,
+                end: left.end,
+                value: Cow::Borrowed(left.value),
+            });
+        }
+        let Some(operation) = &columns.operation else {
+            return LexedLine::Line(current);
+        };
+        let (substring, start, end) = (operation.value, operation.start, operation.end);
+        // Words following the operation, for lines that take them one by one.
+        let words =
+            || split_whitespace_with_index(&code[end..]).map(|(word, index)| (word, end + index));
+        // `MACRO`, `ENDM` and `INCLUDE` cannot be labelled.
+        let first = current.left.is_none();
+        if substring == "INCLUDE" && first {
+            return match parse_include_path(&line_literal[end..]) {
+                Some(path) => LexedLine::Include(path, error_info(start, end)),
+                None => LexedLine::Err(
+                    AssemblerError::IncludePathExpected(error_info(
+                        start,
+                        line_literal.trim_end().len(),
+                    )),
+                    None,
+                ),
+            };
+        } else if let Some(kind) = DirectiveKind::from_string(substring) {
+            if kind == DirectiveKind::Equ && current.left.is_none() {
+                return LexedLine::Err(
+                    AssemblerError::DirectiveExpectsName(
+                        error_info(start, end),
+                        substring.to_string(),
+                    ),
+                    None,
+                );
+            }
+            let Some(operand) = &columns.operand else {
+                return LexedLine::Err(
+                    AssemblerError::DirectiveExpectsOperand(
+                        error_info(start, end),
+                        substring.to_string(),
+                    ),
+                    None,
+                );
+            };
+            return match parse_operand(operand.value, operand.start) {
+                Ok(value) => LexedLine::Directive(Directive {
+                    kind,
+                    left: current.left,
+                    value: LinePart {
+                        start: operand.start,
+                        end: operand.end,
+                        value,
+                    },
+                    error_info: error_info(start, end),
+                }),
+                Err(error) => LexedLine::Err(expression_error(error), None),
+            };
+        } else if substring == "DS" {
+            // `DS 10` is read as ten `DAT 0`.
+            let Some(operand) = &columns.operand else {
+                return LexedLine::Err(
+                    AssemblerError::DirectiveExpectsOperand(
+                        error_info(start, end),
+                        substring.to_string(),
+                    ),
+                    label(&current),
+                );
+            };
+            let (index, operand_end) = (operand.start, operand.end);
+            let count = match parse_operand(operand.value, index) {
+                Ok(value) => {
+                    let count = LinePart {
+                        start: index,
+                        end: operand_end,
+                        value,
+                    };
+                    self.evaluate(&count, &error_info(start, end))
+                }
+                Err(error) => Err(expression_error(error)),
+            };
+            let count = match count {
+                Ok(count) if (1..=100).contains(&count) => count as usize,
+                Ok(_) => {
+                    let info = error_info(index, operand_end);
+                    let err = AssemblerError::InvalidCount(info, operand.value.to_string());
+                    return LexedLine::Err(err, label(&current));
+                }
+                Err(err) => return LexedLine::Err(err, label(&current)),
+            };
+            let zero = LinePart {
+                start: index,
+                end: operand_end,
+                value: RightField::Literal(0),
+            };
+            current.instruction = Some(LinePart {
+                start,
+                end,
+                value: MemonicType::DAT,
+            });
+            current.right = Some(LinePart {
+                start: index,
+                end: operand_end,
+                value: RightField::List(vec![zero; count]),
+            });
+            return LexedLine::Line(current);
+        } else if substring == "MACRO" && first {
+            return self.lex_macro_start(words(), error_info(start, end));
+        } else if substring == "ENDM" && first {
+            return match words().next() {
+                None => LexedLine::MacroEnd(error_info(start, end)),
+                Some((extra, index)) => LexedLine::Err(
+                    EndOfLineExpected(error_info(index, index + extra.len())),
+                    None,
+                ),
+            };
+        } else if let Some(pseudo) = PseudoOp::from_string(substring) {
+            current.pseudo = Some(LinePart {
+                start,
+                end,
+                value: pseudo,
+            });
+        } else if let Some(instruction) = MemonicType::from_string(substring) {
+            current.instruction = Some(LinePart {
+                start,
+                end,
+                value: instruction,
+            });
+        } else if self.macros.contains_key(substring) {
+            let args = words()
+                .map(|(arg, index)| {
+                    Ok(LinePart {
+                        start: index,
+                        end: index + arg.len(),
+                        value: parse_operand(arg, index)?,
+                    })
+                })
+                .collect::<Result<Vec<LinePart<RightField>>, ExpressionError>>();
+            let args = match args {
+                Ok(args) => args,
+                Err(error) => return LexedLine::Err(expression_error(error), label(&current)),
+            };
+            return LexedLine::MacroCall(MacroCall {
+                left: current.left,
+                name: LinePart {
+                    start,
+                    end,
+                    value: substring,
+                },
+                args,
+                line: file_line,
+                source: source.clone(),
+            });
+        } else {
+            let suggest = |name: &str| Hint::did_you_mean(name, self.instruction_names());
+            if let (None, Some(left)) = (suggest(substring), &current.left) {
+                // `LDAA 5` is read as the label LDAA followed by 5, but the label is the typo.
+                if let Some(hint) = suggest(&left.value) {
+                    let mut info = error_info(left.start, left.end);
+                    info.hint = Some(Box::new(hint));
+                    return LexedLine::Err(
+                        AssemblerError::InvalidInstruction(info, left.value.to_string()),
+                        None,
+                    );
+                }
+            }
+            let mut info = error_info(start, end);
+            info.hint = suggest(substring).map(Box::new);
+            return LexedLine::Err(
+                AssemblerError::InvalidInstruction(info, substring.to_string()),
+                label(&current),
+            );
+        }
+        let Some(operand) = &columns.operand else {
+            return LexedLine::Line(current);
+        };
+        let start = operand.start;
+        if let Some((word, _)) = words().next() {
+            if let Some(instruction) = MemonicType::from_string(word) {
+                let end = start + word.len();
+                let Some(first) = current.instruction.as_ref() else {
+                    return LexedLine::Err(
+                        UnexpectedInstruction(error_info(start, end), instruction),
+                        label(&current),
+                    );
+                };
+                // Two instruction names in a row, so one of them is meant as a label.
+                let takes_operand = first.value == MemonicType::DAT
+                    || OpCode::try_from_mnemonic_type(first.value, None).is_err();
+                return if takes_operand {
+                    LexedLine::Err(
+                        AssemblerError::ReservedLabel(error_info(start, end), word.to_string()),
+                        label(&current),
+                    )
+                } else if current.left.is_none() {
+                    LexedLine::Err(
+                        AssemblerError::ReservedLabel(
+                            error_info(first.start, first.end),
+                            first.value.to_string(),
+                        ),
+                        None,
+                    )
+                } else {
+                    LexedLine::Err(
+                        UnexpectedInstruction(error_info(start, end), instruction),
+                        label(&current),
+                    )
+                };
+            }
+        }
+        // The operand runs up to the comment, so expressions may contain spaces.
+        let operand = operand.value;
+        let is_data = current
+            .instruction
+            .as_ref()
+            .is_some_and(|instruction| instruction.value == MemonicType::DAT);
+        let is_move = current
+            .pseudo
+            .as_ref()
+            .is_some_and(|pseudo| pseudo.value == PseudoOp::MOV);
+        let value = if is_data && operand.starts_with('=') {
+            Err(ExpressionError::Invalid(start, start + 1))
+        } else if is_data {
+            parse_data(operand, start)
+        } else if is_move {
+            parse_operands(operand, start)
+        } else {
+            parse_instruction_operand(operand, start)
+        };
+        // Expressions and macro arguments are range checked by the assembler.
+        let value = match value {
+            Ok(RightField::Literal(value)) if !is_data && value > 99 => Err(
+                ExpressionError::AddressOutOfRange(start, start + operand.len()),
+            ),
+            value => value,
+        };
+        match value {
+            Ok(value) => {
+                current.right = Some(LinePart {
+                    start,
+                    end: start + operand.len(),
+                    value,
+                });
+                LexedLine::Line(current)
+            }
+            Err(error) => LexedLine::Err(expression_error(error), label(&current)),
+        }
+    }
+    fn lex_macro_start(
+        &self,
+        mut tokens: impl Iterator<Item = (&'a str, usize)>,
+        error_info: ErrorInfo<'a>,
+    ) -> LexedLine<'a> {
+        let Some((name, index)) = tokens.next() else {
+            return LexedLine::Err(AssemblerError::MacroNameExpected(error_info), None);
+        };
+        let mut name_info = error_info.clone();
+        name_info.start = index;
+        name_info.end = index + name.len();
+        if is_reserved(name) {
+            return LexedLine::Err(AssemblerError::MacroNameExpected(name_info), None);
+        }
+        LexedLine::MacroStart(Macro {
+            name,
+            params: tokens.map(|(param, _)| param).collect(),
+            body: Vec::new(),
+            error_info: name_info,
+        })
+    }
+    /// Records `name` as defined at `info`, reporting it if it already was.
+    fn define(&mut self, name: &str, info: ErrorInfo<'a>) -> bool {
+        if let Some(previous) = self.definitions.get(name) {
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::Redefined(
+                    info,
+                    Box::new(previous.clone()),
+                    name.to_string(),
+                )));
+            false
+        } else {
+            self.definitions.insert(name.to_string(), info);
+            true
+        }
+    }
+    /// Full name of `name`: local labels get the global label they belong to in front,
+    /// `.loop` under `MAIN` becoming `MAIN.loop`. `None` for a local label outside of any
+    /// global label.
+    fn qualify(&self, name: Cow<'a, str>) -> Option<Cow<'a, str>> {
+        match &self.scope {
+            Some(scope) if name.starts_with('.') => Some(Cow::Owned(format!("{}{}", scope, name))),
+            // Macro locals are already unique.
+            None if name.starts_with('.') && !name.contains('@') => None,
+            _ => Some(name),
+        }
+    }
+    /// Qualifies `name` for a definition at `info`, reporting local labels outside of any
+    /// global label.
+    fn qualify_definition(
+        &mut self,
+        name: Cow<'a, str>,
+        info: &ErrorInfo<'a>,
+    ) -> Option<Cow<'a, str>> {
+        let qualified = self.qualify(name.clone());
+        if qualified.is_none() {
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::LocalLabelWithoutScope(
+                    info.clone(),
+                    name.to_string(),
+                )));
+        }
+        qualified
+    }
+    /// Qualifies every local label `field` refers to. Returns false, after reporting them, if
+    /// some are outside of any global label.
+    fn qualify_references(
+        &mut self,
+        field: &mut LinePart<RightField<'a>>,
+        info: &ErrorInfo<'a>,
+    ) -> bool {
+        if let RightField::List(values) = &mut field.value {
+            let mut scoped = true;
+            for value in values {
+                scoped &= self.qualify_references(value, info);
+            }
+            return scoped;
+        }
+        let mut unscoped = Vec::new();
+        let mut qualify = |operand: &mut LinePart<RightField<'a>>| {
+            if let RightField::Label(label) = &operand.value {
+                match self.qualify(label.clone()) {
+                    Some(qualified) => operand.value = RightField::Label(qualified),
+                    None => unscoped.push((operand.start, operand.end, label.to_string())),
+                }
+            }
+        };
+        match &mut field.value {
+            RightField::Expression(expression) => expression.for_each_operand_mut(&mut qualify),
+            _ => qualify(field),
+        }
+        for (start, end, label) in &unscoped {
+            let mut info = info.clone();
+            info.start = *start;
+            info.end = *end;
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::LocalLabelWithoutScope(
+                    info,
+                    label.clone(),
+                )));
+        }
+        unscoped.is_empty()
+    }
+    /// Defines `name` at `address` and returns its full name, `None` if it could not be
+    /// defined.
+    fn define_label(
+        &mut self,
+        name: Cow<'a, str>,
+        info: ErrorInfo<'a>,
+        address: u16,
+    ) -> Option<Cow<'a, str>> {
+        let name = self.qualify_definition(name, &info)?;
+        if !name.contains(['.', '@']) {
+            self.scope = Some(name.clone());
+        }
+        if !self.define(&name, info) {
+            return None;
+        }
+        self.label_lookup.insert(name.to_string(), address);
+        Some(name)
+    }
+    fn push_line(&mut self, mut line: LineStructure<'a>) {
+        // A label on the line itself does not start the subroutine a `RET` returns from.
+        let subroutine = self.scope.clone();
+        if let Some(left) = &line.left {
+            let info = ErrorInfo::new(left.start, left.end, &line);
+            let defined = self.define_label(left.value.clone(), info, self.location as u16);
+            let is_data = line
+                .instruction
+                .as_ref()
+                .is_some_and(|instruction| instruction.value == MemonicType::DAT);
+            if let (Some(name), true) = (defined, is_data) {
+                self.data_labels.insert(name.into_owned());
+            }
+        }
+        let info = ErrorInfo::new(0, 0, &line);
+        if let Some(LinePart {
+            start,
+            end,
+            value: RightField::Constant(constant),
+        }) = &line.right
+        {
+            if let Some(instruction) = line
+                .instruction
+                .as_ref()
+                .filter(|instruction| is_destination(instruction.value))
+            {
+                let info = ErrorInfo::new(*start, *end, &line);
+                let operation = instruction.value.name().to_string();
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::ConstantDestination(
+                        info, operation,
+                    )));
+                return;
+            }
+            let mut constant = constant.as_ref().clone();
+            let mut cell = line.clone();
+            cell.left = None;
+            let Some(label) = self.pool_constant(&mut constant, cell) else {
+                return;
+            };
+            if let Some(right) = line.right.as_mut() {
+                right.value = RightField::Label(Cow::Owned(label));
+            }
+        }
+        if let Some(right) = line.right.as_mut() {
+            if !self.qualify_references(right, &info) {
+                return;
+            }
+        }
+        if let Some(pseudo) = line.pseudo.take() {
+            self.expand_pseudo(line, pseudo, subroutine);
+            return;
+        }
+        let cells = match line.right.clone().map(|right| right.value) {
+            Some(RightField::Text(codes)) => codes
+                .into_iter()
+                .map(|code| LinePart {
+                    start: code.start,
+                    end: code.end,
+                    value: RightField::Literal(code.value),
+                })
+                .collect(),
+            Some(RightField::List(values)) => values,
+            _ => Vec::new(),
+        };
+        if !cells.is_empty() {
+            // Every character of a string or value of a list gets its own cell, the label
+            // names the first one.
+            line.left = None;
+            for value in cells {
+                let mut cell = line.clone();
+                cell.right = Some(value);
+                cell.address = self.next_address();
+                self.pending.push_back(LexerState::Some(cell));
+            }
+            return;
+        }
+        line.address = self.next_address();
+        self.pending.push_back(LexerState::Some(line));
+    }
+    fn next_address(&mut self) -> u16 {
+        let address = self.location;
+        self.location += 1;
+        self.end = self.end.max(self.location);
+        if let Some(used) = self.used.get_mut(address) {
+            *used = true;
+        }
+        address as u16
+    }
+    /// Adds the value of `constant` to the pool, unless it already holds it, and returns the
+    /// label of its cell. `cell` is the line using it, which the cell is mapped to. Constants
+    /// made of numbers and `EQU` names are resolved here, and like `EQU` operands can only use
+    /// names defined above them. Constants using labels are left to the assembler, which
+    /// relocates them in objects.
+    fn pool_constant(
+        &mut self,
+        constant: &mut LinePart<RightField<'a>>,
+        mut cell: LineStructure<'a>,
+    ) -> Option<String> {
+        let info = ErrorInfo::new(constant.start, constant.end, &cell);
+        if !self.qualify_references(constant, &info) {
+            return None;
+        }
+        let labels = constant.value.labels();
+        if !labels
+            .iter()
+            .all(|label| self.constants.contains_key(*label))
+        {
+            let label = format!("={}", pool_name(&constant.value));
+            if !self.pool.iter().any(|(pooled, _)| *pooled == label) {
+                cell.instruction = Some(LinePart {
+                    start: constant.start,
+                    end: constant.end,
+                    value: MemonicType::DAT,
+                });
+                cell.right = Some(constant.clone());
+                self.pool.push((label.clone(), cell));
+            }
+            return Some(label);
+        }
+        for label in labels {
+            self.references.insert(label.to_string());
+        }
+        let value = match self.evaluate(constant, &info) {
+            Ok(value) if (0..=999).contains(&value) => value as u16,
+            Ok(value) => {
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::OperandOutOfRange(
+                        info, value, 999,
+                    )));
+                return None;
+            }
+            Err(err) => {
+                self.pending.push_back(LexerState::Err(err));
+                return None;
+            }
+        };
+        let label = format!("={}", value);
+        if !self.pool.iter().any(|(pooled, _)| *pooled == label) {
+            cell.instruction = Some(LinePart {
+                start: constant.start,
+                end: constant.end,
+                value: MemonicType::DAT,
+            });
+            cell.right = Some(LinePart {
+                start: constant.start,
+                end: constant.end,
+                value: RightField::Literal(value),
+            });
+            self.pool.push((label.clone(), cell));
+        }
+        Some(label)
+    }
+    /// Pushes the real instructions `pseudo` stands for in place of `line`, which holds its
+    /// operands. `subroutine` is the global label above the line.
+    fn expand_pseudo(
+        &mut self,
+        line: LineStructure<'a>,
+        pseudo: LinePart<PseudoOp>,
+        subroutine: Option<Cow<'a, str>>,
+    ) {
+        let info = ErrorInfo::new(pseudo.start, pseudo.end, &line);
+        let arguments = match line.right.clone() {
+            None => Vec::new(),
+            Some(LinePart {
+                value: RightField::List(values),
+                ..
+            }) => values,
+            Some(right) => vec![right],
+        };
+        let Some(expansion) = pseudo.value.expansion(arguments.len()) else {
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::InvalidPseudoOperands(
+                    info,
+                    pseudo.value,
+                )));
+            return;
+        };
+        // Pool constants are shared, so no argument the expansion writes to or branches to may
+        // be one.
+        let destination = expansion
+            .iter()
+            .filter_map(|(mnemonic, operand)| match operand {
+                Operand::Argument(index) if is_destination(*mnemonic) => arguments.get(*index),
+                Operand::SubroutineStart => arguments.first(),
+                _ => None,
+            })
+            .find(|argument| match &argument.value {
+                RightField::Constant(_) => true,
+                RightField::Label(label) => is_pool_label(label),
+                _ => false,
+            });
+        if let Some(argument) = destination {
+            let info = ErrorInfo::new(argument.start, argument.end, &line);
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::ConstantDestination(
+                    info,
+                    pseudo.value.name().to_string(),
+                )));
+            return;
+        }
+        if expansion
+            .iter()
+            .any(|(_, operand)| *operand == Operand::Subroutine)
+        {
+            let Some(subroutine) = &subroutine else {
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::ReturnWithoutSubroutine(
+                        info,
+                    )));
+                return;
+            };
+            self.returns.push((subroutine.to_string(), info));
+        }
+        if let (PseudoOp::CALL, Some(RightField::Label(target))) = (
+            pseudo.value,
+            arguments.first().map(|argument| &argument.value),
+        ) {
+            self.called.insert(target.to_string());
+        }
+        fn at<T>(pseudo: &LinePart<PseudoOp>, value: T) -> LinePart<T> {
+            LinePart {
+                start: pseudo.start,
+                end: pseudo.end,
+                value,
+            }
+        }
+        let part = |value: RightField<'a>| at(&pseudo, value);
+        // Branches past the expansion go to a generated label, so they are relocated in objects.
+        self.expansions += 1;
+        let next = format!("@{}{}", pseudo.value.name(), self.expansions);
+        if expansion
+            .iter()
+            .any(|(_, operand)| matches!(operand, Operand::Next | Operand::ReturnBranch))
+        {
+            let address = (self.location + expansion.len()) as u16;
+            self.label_lookup.insert(next.clone(), address);
+        }
+        for (mnemonic, operand) in expansion {
+            let right = match operand {
+                Operand::Argument(index) => Some(arguments[*index].clone()),
+                Operand::Constant(value) => Some(part(RightField::Constant(Box::new(part(
+                    RightField::Literal(*value),
+                ))))),
+                Operand::SubroutineStart => {
+                    let start = Expression::Operand(arguments[0].clone());
+                    let one = Expression::Operand(part(RightField::Literal(1)));
+                    let sum = Expression::Add(Box::new(start), Box::new(one));
+                    Some(part(RightField::Expression(Box::new(sum))))
+                }
+                Operand::Next => Some(part(RightField::Label(Cow::Owned(next.clone())))),
+                Operand::ReturnBranch => {
+                    // A `BRA` back to the caller, stored over the first cell of the subroutine.
+                    let branch = OpCode::BRA(Some(0)).to_numeric_representation();
+                    let opcode = Expression::Operand(part(RightField::Literal(branch)));
+                    let target =
+                        Expression::Operand(part(RightField::Label(Cow::Owned(next.clone()))));
+                    let sum = Expression::Add(Box::new(opcode), Box::new(target));
+                    let label = format!("={}", next);
+                    let mut cell = line.clone();
+                    cell.left = None;
+                    cell.instruction = Some(at(&pseudo, MemonicType::DAT));
+                    cell.right = Some(part(RightField::Expression(Box::new(sum))));
+                    self.pool.push((label.clone(), cell));
+                    Some(part(RightField::Label(Cow::Owned(label))))
+                }
+                Operand::Subroutine => subroutine
+                    .clone()
+                    .map(|subroutine| part(RightField::Label(subroutine))),
+            };
+            let mut cell = line.clone();
+            cell.left = None;
+            cell.instruction = Some(at(&pseudo, *mnemonic));
+            cell.right = right;
+            self.push_line(cell);
+        }
+    }
+    /// Reports every `RET` whose subroutine does not start with a `DAT` cell or is never
+    /// given to `CALL`. Subroutines exported to other objects may be called from them.
+    fn check_returns(&mut self) {
+        for (subroutine, info) in mem::take(&mut self.returns) {
+            let called = self.called.contains(&subroutine)
+                || self.exports.iter().any(|(name, _)| *name == subroutine);
+            if !called || !self.data_labels.contains(&subroutine) {
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::NotASubroutine(
+                        info, subroutine,
+                    )));
+            }
+        }
+    }
+    /// Places the constant pool in the first run of free cells long enough to hold it, or
+    /// failing that in whichever cells are free. Cells left over go past the end of the
+    /// mailbox, where the assembler reports them.
+    fn place_pool(&mut self) {
+        let free: Vec<usize> = (0..self.used.len())
+            .filter(|address| !self.used[*address])
+            .collect();
+        let size = self.pool.len();
+        let run = free
+            .windows(size)
+            .find(|run| run[size - 1] - run[0] == size - 1);
+        let mut addresses = match run {
+            Some(run) => run.to_vec(),
+            None => free,
+        }
+        .into_iter()
+        .chain(self.end.max(self.used.len())..);
+        for (label, mut cell) in mem::take(&mut self.pool) {
+            self.location = addresses.next().unwrap_or_default();
+            cell.address = self.next_address();
+            self.label_lookup.insert(label, cell.address);
+            self.pending.push_back(LexerState::Some(cell));
+        }
+    }
+    /// Resolves `operand` with the labels defined so far.
+    fn evaluate(
+        &self,
+        operand: &LinePart<RightField<'a>>,
+        error_info: &ErrorInfo<'a>,
+    ) -> Result<i32, AssemblerError<'a>> {
+        match &operand.value {
+            RightField::Literal(value) | RightField::Address(value) => Ok(*value as i32),
+            RightField::Label(label) => match self
+                .constants
+                .get(label.as_ref())
+                .or_else(|| self.label_lookup.get(label.as_ref()))
+            {
+                Some(value) => Ok(*value as i32),
+                None => {
+                    let mut info = error_info.clone();
+                    info.start = operand.start;
+                    info.end = operand.end;
+                    let names = self.constants.keys().chain(self.label_lookup.keys());
+                    Err(AssemblerError::unset_label(
+                        info,
+                        label,
+                        names.map(String::as_str),
+                    ))
+                }
+            },
+            RightField::Expression(expression) => {
+                expression.evaluate(&mut |operand| self.evaluate(operand, error_info))
+            }
+            RightField::Text(_) => unreachable!("strings are only lexed as DAT operands"),
+            RightField::Constant(_) => unreachable!("constants are only lexed as operands"),
+            RightField::List(_) => unreachable!("lists are only lexed as DAT operands"),
+        }
+    }
+    /// Applies `ORG` or `EQU`. Their operand can only use names defined above them.
+    fn apply_directive(&mut self, mut directive: Directive<'a>) {
+        if !self.qualify_references(&mut directive.value, &directive.error_info) {
+            return;
+        }
+        for label in directive.value.value.labels() {
+            self.references.insert(label.to_string());
+        }
+        let max = match directive.kind {
+            DirectiveKind::Org => 99,
+            DirectiveKind::Equ => 999,
+            DirectiveKind::Import | DirectiveKind::Export => {
+                self.apply_linkage(directive);
+                return;
+            }
+        };
+        let value = match self.evaluate(&directive.value, &directive.error_info) {
+            Ok(value) if (0..=max).contains(&value) => value as u16,
+            Ok(value) => {
+                let mut info = directive.error_info;
+                info.start = directive.value.start;
+                info.end = directive.value.end;
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::OperandOutOfRange(
+                        info, value, max as u16,
+                    )));
+                return;
+            }
+            Err(err) => {
+                self.pending.push_back(LexerState::Err(err));
+                return;
+            }
+        };
+        match (directive.kind, directive.left) {
+            (DirectiveKind::Org, left) => {
+                self.location = value as usize;
+                if let Some(left) = left {
+                    let mut info = directive.error_info;
+                    info.start = left.start;
+                    info.end = left.end;
+                    self.define_label(left.value, info, value);
+                }
+            }
+            (DirectiveKind::Equ, Some(left)) => {
+                let mut info = directive.error_info;
+                info.start = left.start;
+                info.end = left.end;
+                let Some(name) = self.qualify_definition(left.value, &info) else {
+                    return;
+                };
+                if self.define(&name, info) {
+                    self.constants.insert(name.into_owned(), value);
+                }
+            }
+            (DirectiveKind::Equ, None) => {
+                unreachable!("EQU without a name is rejected by the lexer")
+            }
+            (DirectiveKind::Import | DirectiveKind::Export, _) => {
+                unreachable!("applied by apply_linkage")
+            }
+        }
+    }
+    /// Applies `IMPORT` or `EXPORT`, whose operand is a global label. A label in front of
+    /// them names the next cell.
+    fn apply_linkage(&mut self, directive: Directive<'a>) {
+        let mut info = directive.error_info;
+        if let Some(left) = directive.left {
+            let mut info = info.clone();
+            info.start = left.start;
+            info.end = left.end;
+            self.define_label(left.value, info, self.location as u16);
+        }
+        info.start = directive.value.start;
+        info.end = directive.value.end;
+        let name = match directive.value.value {
+            RightField::Label(name) if !name.contains(['.', '@']) => name.into_owned(),
+            _ => {
+                let name = info.literal()[info.start..info.end].to_string();
+                self.pending
+                    .push_back(LexerState::Err(AssemblerError::InvalidLabel(info, name)));
+                return;
+            }
+        };
+        if directive.kind == DirectiveKind::Export {
+            self.exports.push((name, info));
+        } else if self.define(&name, info) {
+            self.imports.push(name);
+        }
+    }
+    /// Expands `call` into the pending queue. `invocation` is the call as written in the
+    /// program, which differs from `call` when macros invoke other macros. Macros can only
+    /// use macros defined before them, so expansion always terminates.
+    fn expand(&mut self, call: MacroCall<'a>, invocation: &Invocation<'a>, nested: bool) {
+        let mut name_info = ErrorInfo {
+            start: call.name.start,
+            end: call.name.end,
+            line: call.line,
+            source: call.source.clone(),
+            expansion: None,
+            hint: None,
+        };
+        if nested {
+            name_info = name_info.with_invocation(invocation);
+        }
+        let Some(definition) = self.macros.get(call.name.value).cloned() else {
+            return;
+        };
+        if call.args.len() != definition.params.len() {
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::MacroArgumentCount(
+                    name_info,
+                    definition.name.to_string(),
+                    definition.params.len() as u16,
+                    call.args.len() as u16,
+                )));
+            return;
+        }
+        if let Some(left) = &call.left {
+            let mut info = name_info.clone();
+            info.start = left.start;
+            info.end = left.end;
+            self.define_label(left.value.clone(), info, self.location as u16);
+        }
+        self.expansions += 1;
+        let locals: HashSet<Cow<'a, str>> = definition
+            .body
+            .iter()
+            .filter_map(|line| match line {
+                MacroLine::Line(line) => line.left.as_ref(),
+                MacroLine::Call(call) => call.left.as_ref(),
+                MacroLine::Directive(directive) => directive.left.as_ref(),
+            })
+            .map(|left| left.value.clone())
+            .collect();
+        let expansion = self.expansions;
+        let rename = |label: &mut LinePart<Cow<'a, str>>| {
+            if locals.contains(&label.value) {
+                label.value = Cow::Owned(format!("{}@{}", label.value, expansion));
+            }
+        };
+        let substitute_operand = |field: &mut LinePart<RightField<'a>>| {
+            if let RightField::Label(label) = &field.value {
+                if let Some(index) = definition.params.iter().position(|p| p == label) {
+                    field.value = call.args[index]
+                        .value
+                        .clone()
+                        .with_span(field.start, field.end);
+                } else if locals.contains(label) {
+                    field.value = RightField::Label(Cow::Owned(format!("{}@{}", label, expansion)));
+                }
+            }
+        };
+        let substitute_value = |field: &mut LinePart<RightField<'a>>| {
+            if let RightField::Expression(expression) = &mut field.value {
+                expression.for_each_operand_mut(&mut |operand| substitute_operand(operand));
+            } else {
+                substitute_operand(field);
+            }
+        };
+        let substitute = |field: &mut LinePart<RightField<'a>>| match &mut field.value {
+            RightField::Constant(constant) => substitute_value(constant),
+            RightField::List(values) => values.iter_mut().for_each(substitute_value),
+            _ => substitute_value(field),
+        };
+        for line in definition.body {
+            match line {
+                MacroLine::Line(mut line) => {
+                    if let Some(left) = line.left.as_mut() {
+                        rename(left);
+                    }
+                    if let Some(right) = line.right.as_mut() {
+                        substitute(right);
+                    }
+                    line.invocation = Some(invocation.clone());
+                    self.push_line(line);
+                }
+                MacroLine::Call(mut inner) => {
+                    if let Some(left) = inner.left.as_mut() {
+                        rename(left);
+                    }
+                    inner.args.iter_mut().for_each(substitute);
+                    self.expand(inner, invocation, true);
+                }
+                MacroLine::Directive(mut directive) => {
+                    if let Some(left) = directive.left.as_mut() {
+                        rename(left);
+                    }
+                    substitute(&mut directive.value);
+                    directive.error_info = directive.error_info.with_invocation(invocation);
+                    self.apply_directive(directive);
+                }
+            }
+        }
+    }
+    fn open_include(&mut self, path: &str, info: ErrorInfo<'a>) {
+        let base = self
+            .current_file()
+            .and_then(|file| Path::new(file).parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let resolved = base.join(path);
+        let name = resolved.display().to_string();
+        let canonical = fs::canonicalize(&resolved).ok();
+        if canonical.is_some() && self.open.iter().any(|file| file.canonical == canonical) {
+            self.pending
+                .push_back(LexerState::Err(AssemblerError::IncludeCycle(info, name)));
+            return;
+        }
+        // A file included a second time is lexed again from the text read the first time.
+        let loaded = canonical
+            .as_ref()
+            .and_then(|canonical| self.loaded.get(canonical));
+        let source = match loaded {
+            Some(source) => *source,
+            None => {
+                let Ok(text) = fs::read_to_string(&resolved) else {
+                    self.pending
+                        .push_back(LexerState::Err(AssemblerError::IncludeNotFound(info, name)));
+                    return;
+                };
+                let source = self.store.add(SourceFile {
+                    name: Some(name),
+                    text: Cow::Owned(text),
+                    included_at: Some((info.source.file, info.line)),
+                });
+                self.sources.push(source);
+                if let Some(canonical) = &canonical {
+                    self.loaded.insert(canonical.clone(), source);
+                }
+                source
+            }
+        };
+        self.open.push(OpenFile::new(source, canonical));
+    }
+    fn define_or_emit(&mut self, lexed: LexedLine<'a>) {
+        if let LexedLine::Include(path, info) = lexed {
+            self.open_include(path, info);
+            return;
+        }
+        if let Some(definition) = self.defining.as_mut() {
+            match lexed {
+                LexedLine::Skip => {}
+                LexedLine::Err(e, _) => self.pending.push_back(LexerState::Err(e)),
+                LexedLine::Line(line) => definition.body.push(MacroLine::Line(line)),
+                LexedLine::MacroCall(call) => definition.body.push(MacroLine::Call(call)),
+                LexedLine::Directive(directive) => {
+                    definition.body.push(MacroLine::Directive(directive))
+                }
+                LexedLine::MacroStart(inner) => {
+                    self.pending
+                        .push_back(LexerState::Err(AssemblerError::NestedMacroDefinition(
+                            inner.error_info,
+                        )))
+                }
+                LexedLine::MacroEnd(_) => {
+                    // A redefinition is reported when it starts and its body dropped here.
+                    let definition = self.defining.take().unwrap();
+                    self.macros.entry(definition.name).or_insert(definition);
+                }
+                LexedLine::Include(..) => unreachable!("includes are opened above"),
+            }
+            return;
+        }
+        match lexed {
+            LexedLine::Skip => self.pending.push_back(LexerState::Skip),
+            LexedLine::Err(e, label) => {
+                if let Some((label, info)) = label {
+                    self.define_label(label, info, self.location as u16);
+                }
+                self.pending.push_back(LexerState::Err(e));
+            }
+            LexedLine::Line(line) => self.push_line(line),
+            LexedLine::Directive(directive) => self.apply_directive(directive),
+            LexedLine::MacroStart(definition) => {
+                if self.macros.contains_key(definition.name) {
+                    self.pending
+                        .push_back(LexerState::Err(AssemblerError::MacroRedefined(
+                            definition.error_info.clone(),
+                            definition.name.to_string(),
+                        )));
+                }
+                self.defining = Some(definition);
+            }
+            LexedLine::MacroEnd(info) => self
+                .pending
+                .push_back(LexerState::Err(AssemblerError::UnexpectedEndOfMacro(info))),
+            LexedLine::MacroCall(call) => {
+                let invocation = Invocation {
+                    name: call.name.value,
+                    line: call.line,
+                    start: call.left.as_ref().map_or(call.name.start, |l| l.start),
+                    end: call.args.last().map_or(call.name.end, |arg| arg.end),
+                    source: call.source.clone(),
+                };
+                self.expand(call, &invocation, false);
+            }
+            LexedLine::Include(..) => unreachable!("includes are opened above"),
+        }
+    }
 }
-impl<T: BufRead> Iterator for Lexer<T> {
-    type Item = LexerState;
+impl<'a> Iterator for Lexer<'a> {
+    type Item = LexerState<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lines.next() {
-            Some((file_line, line_literal)) => {
-                let mut expect = TokenType::Any;
-                if let Ok(line_literal) = line_literal {
-                    let mut current = LineStructure::new(file_line as u16);
-                    let trimmed = line_literal.trim();
-                    if trimmed.starts_with("//") {
-                        return Some(LexerState::Skip);
-                    }else if trimmed.is_empty(){
-                        return Some(LexerState::Skip);
+        loop {
+            if let Some(state) = self.pending.pop_front() {
+                return Some(state);
+            }
+            let Some(file) = self.open.last_mut() else {
+                if !self.returns.is_empty() {
+                    self.check_returns();
+                    continue;
+                }
+                if !self.pool.is_empty() {
+                    self.place_pool();
+                    continue;
+                }
+                if let Some(conditional) = self.conditionals.pop() {
+                    return Some(LexerState::Err(AssemblerError::UnterminatedIf(
+                        conditional.error_info,
+                    )));
+                }
+                let definition = self.defining.take()?;
+                return Some(LexerState::Err(AssemblerError::UnterminatedMacro(
+                    definition.error_info,
+                    definition.name.to_string(),
+                )));
+            };
+            match file.next_line() {
+                Some((file_line, source)) => {
+                    if !self.conditional(file_line, &source) {
+                        let lexed = self.lex_line(file_line, &source);
+                        self.define_or_emit(lexed);
                     }
-                    for (substring, index) in split_whitespace_with_index(&line_literal) {
-                        if substring.starts_with("//") {
-                            break;
-                        }
-                        let start = index;
-                        let end = index + substring.len();
-                        if let Some(instruction) = MemonicType::from_string(substring) {
-                            if expect == TokenType::Instruction || expect == TokenType::Any {
-                                expect = TokenType::RightLabel;
-                                current.instruction = Some(LinePart {
-                                    start,
-                                    end,
-                                    value: instruction,
-                                });
-                            } else {
-                                return Some(LexerState::Err(UnexpectedInstruction(
-                                    ErrorInfo {
-                                        start,
-                                        end,
-                                        line: file_line as u16,
-                                        literal: line_literal.clone(),
-                                    },
-                                    instruction,
-                                )));
-                            }
-                        } else if expect == TokenType::LeftLabel || expect == TokenType::Any {
-                            expect = TokenType::Instruction;
-                            self.label_lookup
-                                .insert(substring.to_string(), self.assembly_line as u16);
-                            current.left = Some(LinePart {
-                                start,
-                                end,
-                                value: substring.to_string(),
-                            });
-                        } else if expect == TokenType::RightLabel {
-                            expect = TokenType::Eof;
-                            if substring.starts_with("&"){
-                                let address = substring[1..].parse::<u16>().unwrap();
-                                current.right = Some(LinePart {
-                                    start,
-                                    end,
-                                    value: RightField::Address(address),
-                                });
-                            }else if let Ok(number) = substring.parse::<u16>() {
-                                current.right = Some(LinePart {
-                                    start,
-                                    end,
-                                    value: RightField::Literal(number),
-                                });
-                            } else {
-                                current.right = Some(LinePart {
-                                    start,
-                                    end,
-                                    value: RightField::Label(substring.to_string()),
-                                });
-                            }
-                        } else if expect == TokenType::Eof {
-                            return Some(LexerState::Err(EndOfLineExpected(ErrorInfo {
-                                start,
-                                end,
-                                line: file_line as u16,
-                                literal: line_literal.clone(),
-                            })));
-                        } else {
-                            return Some(LexerState::Err(AssemblerError::InvalidInstruction(
-                                ErrorInfo {
-                                    start,
-                                    end,
-                                    line: file_line as u16,
-                                    literal: line_literal.clone(),
-                                },
-                                substring.to_string(),
-                            )));
-                        }
+                }
+                None => {
+                    if let Some((line, source)) = file.overflow() {
+                        let info = ErrorInfo {
+                            start: 0,
+                            end: source.literal().len(),
+                            line,
+                            source,
+                            expansion: None,
+                            hint: None,
+                        };
+                        let error = AssemblerError::TooManyLines(info, MAX_LINES);
+                        self.pending.push_back(LexerState::Err(error));
                     }
-                    self.assembly_line += 1;
-                    Some(LexerState::Some(current))
-                } else {
-                    panic!("Failed to read line");
+                    self.open.pop();
                 }
             }
-            None => None,
         }
     }
 }
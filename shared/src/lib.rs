@@ -16,4 +16,22 @@ pub mod error;
 #[cfg(feature = "assembler")]
 pub mod lexer;
 #[cfg(feature = "assembler")]
-pub mod assembler;
\ No newline at end of file
+pub mod expression;
+#[cfg(feature = "assembler")]
+pub mod assembler;
+#[cfg(feature = "assembler")]
+pub mod warnings;
+#[cfg(feature = "assembler")]
+pub mod json;
+#[cfg(feature = "assembler")]
+pub mod source_map;
+#[cfg(feature = "assembler")]
+pub mod object;
+#[cfg(feature = "assembler")]
+pub mod listing;
+#[cfg(feature = "assembler")]
+pub mod disassembler;
+#[cfg(feature = "assembler")]
+pub mod formatter;
+#[cfg(feature = "assembler")]
+pub mod pseudo;
\ No newline at end of file
@@ -0,0 +1,187 @@
+use crate::assembler::AssembledProgram;
+use crate::lexer::{is_generated_label, is_pool_label};
+use crate::OpCode;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+/// Assembly listing of a program: every source line next to the addresses and codes it
+/// produced, followed by the constant pool and the symbol table. Programs spread over
+/// several files get one section per file.
+pub struct Listing<'a> {
+    program: &'a AssembledProgram<'a>,
+}
+impl<'a> Listing<'a> {
+    pub fn new(program: &'a AssembledProgram<'a>) -> Self {
+        Self { program }
+    }
+    fn write_row(
+        &self,
+        f: &mut Formatter<'_>,
+        address: u16,
+        labels_by_address: &BTreeMap<u16, &str>,
+    ) -> std::fmt::Result {
+        let decoded = self.program.opcodes[address as usize]
+            .as_ref()
+            .map(decode)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{:>4} {:>4} {:<8} {:<10}",
+            format!("{:02}", address),
+            format!("{:03}", self.program.mailbox[address]),
+            decoded,
+            labels_by_address.get(&address).copied().unwrap_or("")
+        )
+    }
+}
+
+fn decode(opcode: &OpCode) -> String {
+    if let OpCode::DAT(Some(value)) = opcode {
+        format!("DAT {}", value)
+    } else if let Some(address) = opcode.get_address() {
+        format!("{} {:02}", opcode.mnemonic_type(), address)
+    } else {
+        format!("{}", opcode.mnemonic_type())
+    }
+}
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let pool: BTreeMap<u16, &str> = self
+            .program
+            .labels
+            .iter()
+            .filter(|(label, _)| is_pool_label(label))
+            .map(|(label, address)| (*address, label.as_str()))
+            .collect();
+        let mut addresses_by_line: BTreeMap<(u16, u16), Vec<u16>> = BTreeMap::new();
+        for (address, location) in self.program.source_map.iter() {
+            // Pool cells are mapped to the first line using them, but listed on their own.
+            if pool.contains_key(&address) {
+                continue;
+            }
+            addresses_by_line
+                .entry((location.file, location.line))
+                .or_default()
+                .push(address);
+        }
+        // Several labels can share a cell; show the first one alphabetically.
+        let mut labels_by_address: BTreeMap<u16, &str> = BTreeMap::new();
+        for (label, address) in &self.program.labels {
+            if !is_generated_label(label) && !is_pool_label(label) {
+                labels_by_address
+                    .entry(*address)
+                    .and_modify(|shown| *shown = (*shown).min(label.as_str()))
+                    .or_insert(label);
+            }
+        }
+
+        writeln!(
+            f,
+            "{:<4} {:<4} {:<8} {:<10} {:>4}  SOURCE",
+            "ADDR", "CODE", "DECODED", "LABEL", "LINE"
+        )?;
+        for (file, source) in self.program.sources.iter().enumerate() {
+            if self.program.sources.len() > 1 {
+                writeln!(f, "FILE {}", self.program.source_map.files[file])?;
+            }
+            for (line, literal) in source.text.lines().enumerate() {
+                let addresses = addresses_by_line.get(&(file as u16, line as u16));
+                match addresses.and_then(|addresses| addresses.split_first()) {
+                    Some((first, rest)) => {
+                        self.write_row(f, *first, &labels_by_address)?;
+                        writeln!(f, " {:>4}  {}", line + 1, literal)?;
+                        for address in rest {
+                            self.write_row(f, *address, &labels_by_address)?;
+                            writeln!(f)?;
+                        }
+                    }
+                    None => writeln!(f, "{:<29} {:>4}  {}", "", line + 1, literal)?,
+                }
+            }
+        }
+
+        if !pool.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "CONSTANT POOL")?;
+            for address in pool.keys() {
+                self.write_row(f, *address, &pool)?;
+                // The line of the first use, and the constant as written there. The mapped
+                // span starts after the `=`, so it is taken back in.
+                match self.program.source_map.get(*address) {
+                    Some(location) => {
+                        let text = self
+                            .program
+                            .sources
+                            .get(location.file as usize)
+                            .and_then(|source| source.text.lines().nth(location.line as usize))
+                            .and_then(|line| {
+                                let text = line.get(location.start..location.end)?;
+                                match line.get(..location.start) {
+                                    Some(before) if before.ends_with('=') => {
+                                        line.get(location.start - 1..location.end)
+                                    }
+                                    _ => Some(text),
+                                }
+                            })
+                            .unwrap_or_default();
+                        writeln!(f, " {:>4}  {}", location.line + 1, text)?
+                    }
+                    None => writeln!(f)?,
+                }
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "SYMBOL TABLE")?;
+        let symbols: BTreeMap<&String, &u16> = self
+            .program
+            .labels
+            .iter()
+            .filter(|(label, _)| !is_pool_label(label) && !is_generated_label(label))
+            .collect();
+        for (label, address) in symbols {
+            writeln!(f, "{:<16} {:02}", label, address)?;
+        }
+        if !self.program.constants.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "CONSTANTS")?;
+            let constants: BTreeMap<&String, &u16> = self.program.constants.iter().collect();
+            for (name, value) in constants {
+                writeln!(f, "{:<16} {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+    use std::string::ToString;
+
+    #[test]
+    fn lists_pool_constants_as_written() {
+        let sources = Sources::new();
+        let program = assemble(&sources, "LOOP INP\nADD =72\nOUT\nBRA LOOP\n").unwrap();
+        let listing = Listing::new(&program).to_string();
+        assert!(listing.contains("  00  901 INP      LOOP          1  LOOP INP\n"));
+        assert!(listing.contains("\nCONSTANT POOL\n  04  072 DAT 72   =72           2  =72\n"));
+        assert!(listing.ends_with("SYMBOL TABLE\nLOOP             00\n"));
+    }
+
+    #[test]
+    fn shows_the_same_label_for_a_shared_cell() {
+        let sources = Sources::new();
+        let mut program = assemble(&sources, "LOOP INP\nBRA LOOP\n").unwrap();
+        program.labels.insert("AGAIN".to_string(), 0);
+        program.labels.insert("START".to_string(), 0);
+        let listing = Listing::new(&program).to_string();
+        assert!(listing.contains("  00  901 INP      AGAIN         1  LOOP INP\n"));
+    }
+}
@@ -17,6 +17,8 @@ pub enum MailboxError {
     #[cfg(feature = "std")]
     Io(std::io::Error),
     Cast(CheckedCastError),
+    /// More values were given than the mailbox has addresses.
+    TooLarge(usize),
 }
 
 #[derive(Debug)]
@@ -100,12 +102,14 @@ impl IndexMut<u16> for Mailbox {
 }
 
 #[cfg(feature = "std")]
-impl From<Vec<u16>> for Mailbox {
-    fn from(vec: Vec<u16>) -> Self {
-        let mut s: [u16; 100] = [0; 100];
-        for (i, v) in vec.iter().enumerate() {
-            s[i] = *v;
+impl TryFrom<Vec<u16>> for Mailbox {
+    type Error = MailboxError;
+    fn try_from(vec: Vec<u16>) -> Result<Self, MailboxError> {
+        if vec.len() > 100 {
+            return Err(MailboxError::TooLarge(vec.len()));
         }
-        Self(s)
+        let mut s: [u16; 100] = [0; 100];
+        s[..vec.len()].copy_from_slice(&vec);
+        Ok(Self(s))
     }
 }
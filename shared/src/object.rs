@@ -0,0 +1,507 @@
+//! Relocatable objects, assembled separately with [`crate::assembler::assemble_object`] and
+//! combined into one program by [`link`].
+//!
+//! An object is written as a small text file:
+//! ```text
+//! lmc-object 1
+//! name lib/print.lmc
+//! cells 500 902 600
+//! export PRINT 0
+//! import BUFFER
+//! reloc 0 99 0 BUFFER
+//! reloc 2 99 0
+//! lmc-source-map 1
+//! file 0 lib/print.lmc
+//! 0 0 1 8 18
+//! ```
+//! `cells` holds the object from its address 0, with relocated operands left at 0.
+//! `reloc address max addend [symbol]` adds the address of `symbol`, or of the start of the
+//! object, to `addend` and stores it in the operand of `address`. The object ends with its
+//! [`SourceMap`], whose addresses are relative to the start of the object.
+use crate::lexer::LabelLookup;
+use crate::source_map::{self, SourceLocation, SourceMap, SourceMapError};
+use crate::Mailbox;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::string::{String, ToString};
+use std::vec;
+use std::vec::Vec;
+
+const HEADER: &str = "lmc-object 1";
+
+/// An operand the linker fills in once it knows where things are placed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// Address of the cell, relative to the start of the object.
+    pub address: u16,
+    /// Highest value the operand can take, 99 for addresses and 999 for `DAT`.
+    pub max: u16,
+    pub addend: i32,
+    /// Imported label the operand is relative to, `None` for the start of the object.
+    pub symbol: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectFile {
+    /// Source file the object was assembled from, used to name it in link errors.
+    pub name: String,
+    pub cells: Vec<u16>,
+    /// Labels other objects can import, with their address relative to the start.
+    pub exports: Vec<(String, u16)>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub source_map: SourceMap,
+}
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(std::io::Error),
+    MissingHeader,
+    InvalidEntry(usize),
+    SourceMap(SourceMapError),
+    /// The source map has an entry for this address, which is past the last cell.
+    UnmappedAddress(u16),
+    /// A relocation of this address that linking would carry into the opcode, see
+    /// [`ObjectFile::invalid_relocation`].
+    InvalidRelocation(u16),
+}
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::Io(e) => write!(f, "{}", e),
+            ObjectError::MissingHeader => write!(f, "Not an object file"),
+            ObjectError::InvalidEntry(line) => {
+                write!(f, "Invalid object entry at line {}", line + 1)
+            }
+            ObjectError::SourceMap(e) => write!(f, "{}", e),
+            ObjectError::UnmappedAddress(address) => write!(
+                f,
+                "Source map entry for address {} is past the end of the object",
+                address
+            ),
+            ObjectError::InvalidRelocation(address) => write!(
+                f,
+                "Relocation of address {} is repeated or its operand is not 0",
+                address
+            ),
+        }
+    }
+}
+impl ObjectError {
+    /// A stable code identifying the kind of error, such as `"O0003"`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ObjectError::Io(_) => "O0001",
+            ObjectError::MissingHeader => "O0002",
+            ObjectError::InvalidEntry(_) => "O0003",
+            ObjectError::SourceMap(_) => "O0004",
+            ObjectError::UnmappedAddress(_) => "O0005",
+            ObjectError::InvalidRelocation(_) => "O0006",
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectError::Io(_) => "Io",
+            ObjectError::MissingHeader => "MissingHeader",
+            ObjectError::InvalidEntry(_) => "InvalidEntry",
+            ObjectError::SourceMap(_) => "SourceMap",
+            ObjectError::UnmappedAddress(_) => "UnmappedAddress",
+            ObjectError::InvalidRelocation(_) => "InvalidRelocation",
+        }
+    }
+}
+
+impl ObjectFile {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "{}", HEADER)?;
+        writeln!(writer, "name {}", self.name)?;
+        write!(writer, "cells")?;
+        for cell in &self.cells {
+            write!(writer, " {}", cell)?;
+        }
+        writeln!(writer)?;
+        for (name, address) in &self.exports {
+            writeln!(writer, "export {} {}", name, address)?;
+        }
+        for name in &self.imports {
+            writeln!(writer, "import {}", name)?;
+        }
+        for relocation in &self.relocations {
+            write!(
+                writer,
+                "reloc {} {} {}",
+                relocation.address, relocation.max, relocation.addend
+            )?;
+            if let Some(symbol) = &relocation.symbol {
+                write!(writer, " {}", symbol)?;
+            }
+            writeln!(writer)?;
+        }
+        self.source_map.write_to(writer)
+    }
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self, ObjectError> {
+        let mut object = Self {
+            name: String::new(),
+            cells: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+            relocations: Vec::new(),
+            source_map: SourceMap::new(),
+        };
+        let mut lines = reader.lines().enumerate();
+        match lines.next() {
+            Some((_, Ok(header))) if header.trim() == HEADER => {}
+            Some((_, Err(e))) => return Err(ObjectError::Io(e)),
+            _ => return Err(ObjectError::MissingHeader),
+        }
+        let mut source_map = None;
+        for (index, line) in lines.by_ref() {
+            let line = line.map_err(ObjectError::Io)?;
+            if line.trim() == source_map::HEADER {
+                source_map = Some(line);
+                break;
+            }
+            let invalid = || ObjectError::InvalidEntry(index);
+            // Exports and relocations come after the cells they point into.
+            let size = object.cells.len();
+            let parse_address = |address: &str| {
+                address
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|address| (*address as usize) < size)
+            };
+            let (kind, rest) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            match (kind, fields.as_slice()) {
+                ("", []) => {}
+                ("name", _) => object.name = rest.to_string(),
+                // Cells cannot shrink from under the addresses already read.
+                ("cells", cells) if object.exports.is_empty() && object.relocations.is_empty() => {
+                    object.cells = cells
+                        .iter()
+                        .map(|cell| cell.parse::<u16>().ok().filter(|cell| *cell <= 999))
+                        .collect::<Option<_>>()
+                        .ok_or_else(invalid)?;
+                }
+                ("export", [name, address]) => {
+                    let address = parse_address(address).ok_or_else(invalid)?;
+                    object.exports.push((name.to_string(), address));
+                }
+                ("import", [name]) => object.imports.push(name.to_string()),
+                ("reloc", [address, max, addend, symbol @ ..]) if symbol.len() <= 1 => {
+                    object.relocations.push(Relocation {
+                        address: parse_address(address).ok_or_else(invalid)?,
+                        max: max
+                            .parse()
+                            .ok()
+                            .filter(|max| *max <= 999)
+                            .ok_or_else(invalid)?,
+                        addend: addend.parse().map_err(|_| invalid())?,
+                        symbol: symbol.first().map(|symbol| symbol.to_string()),
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        // The source map is read from its own header on.
+        if let Some(header) = source_map {
+            let mut text = header;
+            for (_, line) in lines {
+                text.push('\n');
+                text.push_str(&line.map_err(ObjectError::Io)?);
+            }
+            object.source_map =
+                SourceMap::read_from(text.as_bytes()).map_err(ObjectError::SourceMap)?;
+        }
+        let size = object.cells.len();
+        if let Some((address, _)) = object
+            .source_map
+            .iter()
+            .find(|(address, _)| *address as usize >= size)
+        {
+            return Err(ObjectError::UnmappedAddress(address));
+        }
+        if let Some(address) = object.invalid_relocation() {
+            return Err(ObjectError::InvalidRelocation(address));
+        }
+        Ok(object)
+    }
+    /// The address of the first relocation that shares its cell with another, or whose
+    /// operand is not left at 0. Adding to such an operand could carry into the opcode.
+    pub fn invalid_relocation(&self) -> Option<u16> {
+        let mut relocated = HashSet::new();
+        self.relocations
+            .iter()
+            .find(|relocation| {
+                !relocated.insert(relocation.address)
+                    || !matches!(relocation.max, 99 | 999)
+                    || self
+                        .cells
+                        .get(relocation.address as usize)
+                        .is_none_or(|cell| cell % (relocation.max + 1) != 0)
+            })
+            .map(|relocation| relocation.address)
+    }
+    pub fn export_to_file(&self, file: &mut File) -> Result<(), ObjectError> {
+        self.write_to(file).map_err(ObjectError::Io)
+    }
+    pub fn read_from_file(file: &mut File) -> Result<Self, ObjectError> {
+        Self::read_from(BufReader::new(file))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// A label imported by the object named in the second field that no object exports.
+    UndefinedSymbol(String, String),
+    /// A label exported by both objects named in the other fields.
+    DuplicateSymbol(String, String, String),
+    /// The objects need the given number of cells, more than the mailbox has.
+    ProgramTooLarge(usize),
+    /// An operand of an object, at the given address of the object, that is outside
+    /// `0..=max` once linked.
+    OperandOutOfRange(String, u16, i32, u16),
+    /// A relocation of the object named in the first field, at the given address of the
+    /// object, that would change the instruction, see [`ObjectFile::invalid_relocation`].
+    InvalidRelocation(String, u16),
+}
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol(symbol, object) => {
+                write!(f, "Undefined symbol {} imported by {}", symbol, object)
+            }
+            LinkError::DuplicateSymbol(symbol, first, second) => write!(
+                f,
+                "Symbol {} is exported by both {} and {}",
+                symbol, first, second
+            ),
+            LinkError::ProgramTooLarge(needed) => write!(
+                f,
+                "Program needs {} cells but only 100 are available",
+                needed
+            ),
+            LinkError::OperandOutOfRange(object, address, value, max) => write!(
+                f,
+                "Operand at address {} of {} is {} once linked, expected 0 to {}",
+                address, object, value, max
+            ),
+            LinkError::InvalidRelocation(object, address) => write!(
+                f,
+                "Relocation of address {} of {} is repeated or would change the instruction",
+                address, object
+            ),
+        }
+    }
+}
+impl LinkError {
+    /// A stable code identifying the kind of error, such as `"L0001"`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LinkError::UndefinedSymbol(..) => "L0001",
+            LinkError::DuplicateSymbol(..) => "L0002",
+            LinkError::ProgramTooLarge(..) => "L0003",
+            LinkError::OperandOutOfRange(..) => "L0004",
+            LinkError::InvalidRelocation(..) => "L0005",
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkError::UndefinedSymbol(..) => "UndefinedSymbol",
+            LinkError::DuplicateSymbol(..) => "DuplicateSymbol",
+            LinkError::ProgramTooLarge(..) => "ProgramTooLarge",
+            LinkError::OperandOutOfRange(..) => "OperandOutOfRange",
+            LinkError::InvalidRelocation(..) => "InvalidRelocation",
+        }
+    }
+    /// Name of the object the error is in, `None` when it is about the whole program.
+    pub fn object(&self) -> Option<&str> {
+        match self {
+            LinkError::UndefinedSymbol(_, object)
+            | LinkError::DuplicateSymbol(_, _, object)
+            | LinkError::OperandOutOfRange(object, ..)
+            | LinkError::InvalidRelocation(object, _) => Some(object),
+            LinkError::ProgramTooLarge(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LinkedProgram {
+    pub mailbox: Mailbox,
+    /// Every exported label, at its final address.
+    pub labels: LabelLookup,
+    pub source_map: SourceMap,
+}
+
+/// Places `objects` one after the other from address 0 and fills in every operand that
+/// refers to a label of another object or to the object's own labels.
+pub fn link(objects: &[ObjectFile]) -> Result<LinkedProgram, Vec<LinkError>> {
+    let mut errors = Vec::new();
+    let mut bases = Vec::new();
+    let mut size = 0;
+    for object in objects {
+        bases.push(size);
+        size += object.cells.len();
+    }
+    if size > 100 {
+        return Err(vec![LinkError::ProgramTooLarge(size)]);
+    }
+
+    let mut labels = LabelLookup::new();
+    let mut exported_by: HashMap<&str, &str> = HashMap::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        for (name, address) in &object.exports {
+            if let Some(first) = exported_by.insert(name, &object.name) {
+                errors.push(LinkError::DuplicateSymbol(
+                    name.clone(),
+                    first.to_string(),
+                    object.name.clone(),
+                ));
+                continue;
+            }
+            labels.insert(name.clone(), *base as u16 + address);
+        }
+    }
+
+    let mut mailbox = Mailbox::new();
+    let mut source_map = SourceMap::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        for (address, cell) in object.cells.iter().enumerate() {
+            mailbox[base + address] = *cell;
+        }
+        for import in &object.imports {
+            if !labels.contains_key(import) {
+                errors.push(LinkError::UndefinedSymbol(
+                    import.clone(),
+                    object.name.clone(),
+                ));
+            }
+        }
+        if let Some(address) = object.invalid_relocation() {
+            errors.push(LinkError::InvalidRelocation(object.name.clone(), address));
+            continue;
+        }
+        for relocation in &object.relocations {
+            let target = match &relocation.symbol {
+                None => *base as i32,
+                Some(symbol) => match labels.get(symbol) {
+                    Some(address) => *address as i32,
+                    // Reported above, or exported by no object at all.
+                    None => continue,
+                },
+            };
+            let value = target + relocation.addend;
+            if !(0..=relocation.max as i32).contains(&value) {
+                errors.push(LinkError::OperandOutOfRange(
+                    object.name.clone(),
+                    relocation.address,
+                    value,
+                    relocation.max,
+                ));
+                continue;
+            }
+            let cell = mailbox[base + relocation.address as usize] + value as u16;
+            if cell > 999 {
+                errors.push(LinkError::InvalidRelocation(
+                    object.name.clone(),
+                    relocation.address,
+                ));
+                continue;
+            }
+            mailbox[base + relocation.address as usize] = cell;
+        }
+        for (address, location) in object.source_map.iter() {
+            let file = source_map.add_file(object.source_map.file_name(location));
+            source_map.set(*base as u16 + address, SourceLocation { file, ..*location });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(LinkedProgram {
+        mailbox,
+        labels,
+        source_map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_object, AssemblerOptions};
+    use crate::lexer::Sources;
+
+    fn object(source: &str) -> ObjectFile {
+        let sources = Sources::new();
+        let options = AssemblerOptions::default();
+        assemble_object(&sources, source, &options).unwrap().0
+    }
+
+    fn read(text: &str) -> Result<ObjectFile, ObjectError> {
+        ObjectFile::read_from(text.as_bytes())
+    }
+
+    #[test]
+    fn links_imported_labels() {
+        let main = object("IMPORT PRINT\nLDA X\nBRA PRINT\nX DAT 7");
+        let lib = object("EXPORT PRINT\nPRINT OUT\nHLT");
+        let program = link(&[main, lib]).unwrap();
+        let cells: Vec<u16> = (0..5usize)
+            .map(|address| program.mailbox[address])
+            .collect();
+        assert_eq!(cells, [502, 603, 7, 902, 0]);
+        assert_eq!(program.labels.get("PRINT"), Some(&3));
+    }
+
+    #[test]
+    fn relocates_pool_constants() {
+        let first = object("DAT 1");
+        let second = object("BRA START\nTABLE DAT 7\nSTART LDA =TABLE\nOUT\nHLT");
+        assert!(second
+            .relocations
+            .iter()
+            .any(|relocation| relocation.address == 5));
+        let program = link(&[first, second]).unwrap();
+        assert_eq!(program.mailbox[3usize], 506);
+        assert_eq!(program.mailbox[6usize], 2);
+    }
+
+    #[test]
+    fn objects_read_back_as_written() {
+        let written = object("IMPORT PRINT\nLDA X\nBRA PRINT\nX DAT 7");
+        let mut text = Vec::new();
+        written.write_to(&mut text).unwrap();
+        let read = ObjectFile::read_from(text.as_slice()).unwrap();
+        assert_eq!(read.cells, written.cells);
+        assert_eq!(read.imports, written.imports);
+        assert_eq!(read.relocations, written.relocations);
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        let too_large = read("lmc-object 1\ncells 0\nreloc 0 5000 4000");
+        assert!(matches!(too_large, Err(ObjectError::InvalidEntry(2))));
+        let past_end = read("lmc-object 1\ncells 0\nlmc-source-map 1\nfile 0 a\n50 0 0 0 1");
+        assert!(matches!(past_end, Err(ObjectError::UnmappedAddress(50))));
+        let shrunk = read("lmc-object 1\ncells 0 0\nreloc 1 99 0\ncells 0");
+        assert!(matches!(shrunk, Err(ObjectError::InvalidEntry(3))));
+        let set = read("lmc-object 1\ncells 0 550\nreloc 1 99 60");
+        assert!(matches!(set, Err(ObjectError::InvalidRelocation(1))));
+        let repeated = read("lmc-object 1\ncells 500\nreloc 0 99 60\nreloc 0 99 60");
+        assert!(matches!(repeated, Err(ObjectError::InvalidRelocation(0))));
+    }
+
+    #[test]
+    fn link_keeps_opcodes() {
+        let mut object = object("LDA X\nX DAT");
+        object.cells[0] = 550;
+        let name = object.name.clone();
+        assert_eq!(
+            link(&[object]).unwrap_err(),
+            [LinkError::InvalidRelocation(name, 0)]
+        );
+    }
+}
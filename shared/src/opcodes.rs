@@ -11,6 +11,8 @@ macro_rules! mnemonics_type_enum {
             )*
         }
         impl MemonicType{
+            /// Every mnemonic, in declaration order.
+            pub const ALL: &'static [MemonicType] = &[$(MemonicType::$name),*];
             pub fn from_string(s: &str)->Option<MemonicType>{
                 match s {
                     $(
@@ -19,6 +21,13 @@ macro_rules! mnemonics_type_enum {
                     _ => None,
                 }
             }
+            pub fn name(&self)->&'static str{
+                match self {
+                    $(
+                    MemonicType::$name => stringify!($name),
+                    )*
+                }
+            }
         }
         impl Display for MemonicType{
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -41,7 +50,7 @@ pub enum OpCodeError {
 }
 macro_rules! op_code_enum {
     ($($name:ident),*)=>{
-        #[derive(Debug)]
+        #[derive(Debug, Clone)]
         pub enum OpCode{
             $(
                 $name(Option<u16>),
@@ -70,6 +79,21 @@ macro_rules! op_code_enum {
                     )*
                 }
             }
+            pub fn mnemonic_type(&self)->MemonicType{
+                match self {
+                    $(
+                    OpCode::$name(_) => MemonicType::$name,
+                    )*
+                }
+            }
+            /// The same instruction with its address replaced by `address`.
+            pub fn with_address(&self, address: Option<u16>)->OpCode{
+                match self {
+                    $(
+                    OpCode::$name(_) => OpCode::$name(address),
+                    )*
+                }
+            }
         }
     }
 }
@@ -0,0 +1,123 @@
+//! Pseudo-instructions, each standing for a short sequence of real instructions:
+//!
+//! | Pseudo-op | Expansion                               |
+//! |-----------|-----------------------------------------|
+//! | `INC X`   | `LDA X`, `ADD =1`, `STA X`              |
+//! | `DEC X`   | `LDA X`, `SUB =1`, `STA X`              |
+//! | `CLR`     | `LDA =0`                                |
+//! | `CLR X`   | `LDA =0`, `STA X`                       |
+//! | `MOV A B` | `LDA A`, `STA B`                        |
+//! | `JZ L`    | `BRZ L`                                 |
+//! | `JNZ L`   | `BRZ` past the expansion, `BRA L`       |
+//! | `JN L`    | `BRP` past the expansion, `BRA L`       |
+//! | `CALL S`  | `LDA` return branch, `STA S`, `BRA S+1` |
+//! | `RET`     | `BRA` to the subroutine                 |
+//!
+//! `CALL` and `RET` use the self-modifying return address: a subroutine starts with a `DAT`
+//! cell, `CALL` stores a branch back to the caller there and jumps to the next cell, and
+//! `RET` branches to the stored branch. `RET` belongs to the subroutine named by the last
+//! global label, so labels inside a subroutine should be local.
+//!
+//! Most pseudo-ops go through the accumulator, and `CALL` overwrites it with the return
+//! branch, so arguments cannot be passed to a subroutine in the accumulator.
+use crate::MemonicType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PseudoOp {
+    INC,
+    DEC,
+    CLR,
+    MOV,
+    JZ,
+    JNZ,
+    JN,
+    CALL,
+    RET,
+}
+
+/// Operand of an instruction in the expansion of a pseudo-op.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Operand {
+    /// The operand of the pseudo-op at this index.
+    Argument(usize),
+    /// `=value`, placed in the constant pool.
+    Constant(u16),
+    /// The first operand plus one, where the code of a subroutine starts.
+    SubroutineStart,
+    /// The address following the expansion.
+    Next,
+    /// A pool constant holding a branch to the address following the expansion.
+    ReturnBranch,
+    /// The subroutine the pseudo-op is in.
+    Subroutine,
+}
+
+type Expansion = &'static [(MemonicType, Operand)];
+
+impl PseudoOp {
+    pub const ALL: &'static [PseudoOp] = &[
+        PseudoOp::INC,
+        PseudoOp::DEC,
+        PseudoOp::CLR,
+        PseudoOp::MOV,
+        PseudoOp::JZ,
+        PseudoOp::JNZ,
+        PseudoOp::JN,
+        PseudoOp::CALL,
+        PseudoOp::RET,
+    ];
+    pub fn from_string(s: &str) -> Option<PseudoOp> {
+        Self::ALL.iter().copied().find(|op| op.name() == s)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            PseudoOp::INC => "INC",
+            PseudoOp::DEC => "DEC",
+            PseudoOp::CLR => "CLR",
+            PseudoOp::MOV => "MOV",
+            PseudoOp::JZ => "JZ",
+            PseudoOp::JNZ => "JNZ",
+            PseudoOp::JN => "JN",
+            PseudoOp::CALL => "CALL",
+            PseudoOp::RET => "RET",
+        }
+    }
+    /// How the pseudo-op is written, shown when its operands are wrong.
+    pub fn usage(&self) -> &'static str {
+        match self {
+            PseudoOp::INC => "INC ADDRESS",
+            PseudoOp::DEC => "DEC ADDRESS",
+            PseudoOp::CLR => "CLR or CLR ADDRESS",
+            PseudoOp::MOV => "MOV FROM TO",
+            PseudoOp::JZ => "JZ ADDRESS",
+            PseudoOp::JNZ => "JNZ ADDRESS",
+            PseudoOp::JN => "JN ADDRESS",
+            PseudoOp::CALL => "CALL SUBROUTINE",
+            PseudoOp::RET => "RET",
+        }
+    }
+    /// The instructions the pseudo-op stands for when given `operands` operands, `None` if
+    /// it does not take that many.
+    pub(crate) fn expansion(&self, operands: usize) -> Option<Expansion> {
+        use MemonicType::*;
+        use Operand::{Argument, Constant, Next, ReturnBranch, Subroutine, SubroutineStart};
+        let expansion: Expansion = match (self, operands) {
+            (PseudoOp::INC, 1) => &[(LDA, Argument(0)), (ADD, Constant(1)), (STA, Argument(0))],
+            (PseudoOp::DEC, 1) => &[(LDA, Argument(0)), (SUB, Constant(1)), (STA, Argument(0))],
+            (PseudoOp::CLR, 0) => &[(LDA, Constant(0))],
+            (PseudoOp::CLR, 1) => &[(LDA, Constant(0)), (STA, Argument(0))],
+            (PseudoOp::MOV, 2) => &[(LDA, Argument(0)), (STA, Argument(1))],
+            (PseudoOp::JZ, 1) => &[(BRZ, Argument(0))],
+            (PseudoOp::JNZ, 1) => &[(BRZ, Next), (BRA, Argument(0))],
+            (PseudoOp::JN, 1) => &[(BRP, Next), (BRA, Argument(0))],
+            (PseudoOp::CALL, 1) => &[
+                (LDA, ReturnBranch),
+                (STA, Argument(0)),
+                (BRA, SubroutineStart),
+            ],
+            (PseudoOp::RET, 0) => &[(BRA, Subroutine)],
+            _ => return None,
+        };
+        Some(expansion)
+    }
+}
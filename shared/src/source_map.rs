@@ -0,0 +1,155 @@
+//! Maps every mailbox address back to the source text it was assembled from.
+//!
+//! A source map is written next to the assembled `.bin` as a small text file:
+//! ```text
+//! lmc-source-map 1
+//! file 0 examples/countdown.txt
+//! 2 0 2 5 13
+//! ```
+//! `file` lines list the source files, every other line is
+//! `address file-index line start end` with a zero based line and a column range.
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+pub(crate) const HEADER: &str = "lmc-source-map 1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLocation {
+    /// Index into [`SourceMap::files`].
+    pub file: u16,
+    pub line: u16,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug)]
+pub enum SourceMapError {
+    Io(std::io::Error),
+    MissingHeader,
+    InvalidEntry(usize),
+}
+impl Display for SourceMapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceMapError::Io(e) => write!(f, "{}", e),
+            SourceMapError::MissingHeader => write!(f, "Not a source map"),
+            SourceMapError::InvalidEntry(line) => {
+                write!(f, "Invalid source map entry at line {}", line + 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    pub files: Vec<String>,
+    locations: [Option<SourceLocation>; 100],
+}
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self {
+            files: Vec::new(),
+            locations: [None; 100],
+        }
+    }
+    /// Returns the index of `file`, adding it to the file table if needed.
+    pub fn add_file(&mut self, file: &str) -> u16 {
+        if let Some(index) = self.files.iter().position(|f| f == file) {
+            index as u16
+        } else {
+            self.files.push(file.to_string());
+            (self.files.len() - 1) as u16
+        }
+    }
+    pub fn set(&mut self, address: u16, location: SourceLocation) {
+        self.locations[address as usize] = Some(location);
+    }
+    pub fn get(&self, address: u16) -> Option<&SourceLocation> {
+        self.locations
+            .get(address as usize)
+            .and_then(Option::as_ref)
+    }
+    pub fn file_name(&self, location: &SourceLocation) -> &str {
+        &self.files[location.file as usize]
+    }
+    /// Iterates over every mapped address in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &SourceLocation)> {
+        self.locations
+            .iter()
+            .enumerate()
+            .filter_map(|(address, location)| location.as_ref().map(|l| (address as u16, l)))
+    }
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "{}", HEADER)?;
+        for (index, file) in self.files.iter().enumerate() {
+            writeln!(writer, "file {} {}", index, file)?;
+        }
+        for (address, location) in self.iter() {
+            writeln!(
+                writer,
+                "{} {} {} {} {}",
+                address, location.file, location.line, location.start, location.end
+            )?;
+        }
+        Ok(())
+    }
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self, SourceMapError> {
+        let mut source_map = Self::new();
+        let mut lines = reader.lines().enumerate();
+        match lines.next() {
+            Some((_, Ok(header))) if header.trim() == HEADER => {}
+            Some((_, Err(e))) => return Err(SourceMapError::Io(e)),
+            _ => return Err(SourceMapError::MissingHeader),
+        }
+        for (index, line) in lines {
+            let line = line.map_err(SourceMapError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(file) = line.strip_prefix("file ") {
+                let (_, name) = file
+                    .split_once(' ')
+                    .ok_or(SourceMapError::InvalidEntry(index))?;
+                source_map.files.push(name.to_string());
+                continue;
+            }
+            let fields = line
+                .split_whitespace()
+                .map(|field| field.parse::<usize>())
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|_| SourceMapError::InvalidEntry(index))?;
+            match fields.as_slice() {
+                [address, file, line, start, end]
+                    if *address < 100 && *file < source_map.files.len() =>
+                {
+                    source_map.set(
+                        *address as u16,
+                        SourceLocation {
+                            file: *file as u16,
+                            line: *line as u16,
+                            start: *start,
+                            end: *end,
+                        },
+                    );
+                }
+                _ => return Err(SourceMapError::InvalidEntry(index)),
+            }
+        }
+        Ok(source_map)
+    }
+    pub fn export_to_file(&self, file: &mut File) -> Result<(), SourceMapError> {
+        self.write_to(file).map_err(SourceMapError::Io)
+    }
+    pub fn read_from_file(file: &mut File) -> Result<Self, SourceMapError> {
+        Self::read_from(BufReader::new(file))
+    }
+}
@@ -0,0 +1,189 @@
+//! Checks for programs that assemble but probably do not do what was meant.
+//!
+//! A warning is silenced by a comment on its line naming its kind, e.g.
+//! `STA LOOP // @allow self-modifying`. Inside a macro the comment can be on the line of
+//! the macro body or on the line invoking the macro.
+use crate::error::{AssemblerWarning, ErrorInfo, WarningKind};
+use crate::lexer::{split_comment, LabelLookup, LexerResult, LineStructure};
+use crate::OpCode;
+use std::collections::{HashMap, HashSet};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+fn allows(literal: &str, kind: WarningKind) -> bool {
+    let Some(comment) = split_comment(literal).1 else {
+        return false;
+    };
+    comment.split("@allow").skip(1).any(|names| {
+        names
+            .split(|char: char| char.is_whitespace() || char == ',')
+            .filter(|name| !name.is_empty())
+            .take_while(|name| !name.starts_with('@'))
+            .any(|name| name == kind.name())
+    })
+}
+
+fn is_allowed(warning: &AssemblerWarning) -> bool {
+    let info = warning.info();
+    allows(info.literal(), warning.kind())
+        || info
+            .expansion
+            .as_ref()
+            .is_some_and(|expansion| allows(expansion.invocation.literal(), warning.kind()))
+}
+
+/// Whether `line` is a cell reserved by `DS`, which unlike `DAT` is not meant to be read
+/// before it is stored to.
+fn is_reserved_cell(line: &LineStructure) -> bool {
+    line.instruction.as_ref().is_some_and(|instruction| {
+        line.source
+            .literal()
+            .get(instruction.start..instruction.end)
+            == Some("DS")
+    })
+}
+
+/// Finds every warning in an assembled program.
+///
+/// `definitions` tells where each label was defined and `references` holds the names used
+/// outside of instruction operands, such as by `EQU`.
+pub fn find_warnings<'a>(
+    lines: &LexerResult<'a>,
+    opcodes: &[Option<OpCode>; 100],
+    labels: &LabelLookup,
+    definitions: &HashMap<String, ErrorInfo<'a>>,
+    references: &HashSet<String>,
+) -> Vec<AssemblerWarning<'a>> {
+    let mut warnings = Vec::new();
+    let is_code = |address: u16| {
+        opcodes
+            .get(address as usize)
+            .and_then(Option::as_ref)
+            .is_some_and(|opcode| !matches!(opcode, OpCode::DAT(_)))
+    };
+
+    let mut used: HashSet<&str> = references.iter().map(String::as_str).collect();
+    for line in lines.iter().flatten() {
+        if let Some(right) = &line.right {
+            used.extend(right.value.labels());
+        }
+    }
+    let mut unused: Vec<(&String, &u16)> = labels
+        .iter()
+        // Labels local to a macro expansion are named `label@N`.
+        .filter(|(label, _)| !used.contains(label.as_str()) && !label.contains('@'))
+        .collect();
+    unused.sort_by_key(|(label, address)| (**address, label.to_string()));
+    for (label, _) in unused {
+        if let Some(info) = definitions.get(label) {
+            warnings.push(AssemblerWarning::UnusedLabel(info.clone(), label.clone()));
+        }
+    }
+
+    let written: HashSet<u16> = opcodes
+        .iter()
+        .filter_map(|opcode| match opcode {
+            Some(OpCode::STA(Some(address))) => Some(*address),
+            _ => None,
+        })
+        .collect();
+    // Includes the labels generated for branches of pseudo-instructions.
+    let labelled: HashSet<u16> = labels.values().copied().collect();
+    for (address, line) in lines.iter().enumerate() {
+        let (Some(line), Some(opcode)) = (line, &opcodes[address]) else {
+            continue;
+        };
+        let operand_info = || {
+            let (start, end) = line
+                .right
+                .as_ref()
+                .map_or_else(|| line.span(), |right| (right.start, right.end));
+            ErrorInfo::new(start, end, line)
+        };
+        if address > 0 && !labelled.contains(&(address as u16)) && is_code(address as u16) {
+            if let Some(previous @ (OpCode::BRA(_) | OpCode::HLT(_) | OpCode::COB(_))) =
+                &opcodes[address - 1]
+            {
+                let (start, end) = line.span();
+                warnings.push(AssemblerWarning::UnreachableCode(
+                    ErrorInfo::new(start, end, line),
+                    previous.mnemonic_type(),
+                ));
+            }
+        }
+        match opcode {
+            OpCode::LDA(Some(target)) | OpCode::ADD(Some(target)) | OpCode::SUB(Some(target))
+                if !written.contains(target) =>
+            {
+                let uninitialized = lines
+                    .get(*target as usize)
+                    .and_then(Option::as_ref)
+                    .is_some_and(is_reserved_cell);
+                if uninitialized {
+                    warnings.push(AssemblerWarning::UninitializedRead(
+                        operand_info(),
+                        opcode.mnemonic_type(),
+                        *target,
+                    ));
+                }
+            }
+            _ => {}
+        }
+        if let OpCode::LDA(Some(target)) | OpCode::STA(Some(target)) = opcode {
+            if is_code(*target) {
+                warnings.push(AssemblerWarning::SelfModifying(
+                    operand_info(),
+                    opcode.mnemonic_type(),
+                    *target,
+                ));
+            }
+        }
+    }
+    warnings.retain(|warning| !is_allowed(warning));
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::lexer::Sources;
+    use std::vec::Vec;
+
+    /// Names of the kinds of warning `source` assembles with, in order.
+    fn warnings(source: &str) -> Vec<&'static str> {
+        let sources = Sources::new();
+        let program = assemble(&sources, source).unwrap();
+        program
+            .warnings
+            .iter()
+            .map(|warning| warning.kind().name())
+            .collect()
+    }
+
+    #[test]
+    fn finds_each_kind_of_warning() {
+        assert_eq!(warnings("HLT\nX DAT"), ["unused-label"]);
+        assert_eq!(warnings("BRA L\nOUT\nL HLT"), ["unreachable"]);
+        assert_eq!(warnings("L LDA L\nHLT"), ["self-modifying"]);
+        assert_eq!(warnings("LDA X\nHLT\nX DS 2"), ["uninitialized"]);
+    }
+
+    #[test]
+    fn bare_dat_and_stored_cells_are_initialized() {
+        assert!(warnings("LDA ZERO\nHLT\nZERO DAT").is_empty());
+        assert!(warnings("STA X\nLDA X\nHLT\nX DS 1").is_empty());
+    }
+
+    #[test]
+    fn pragmas_silence_their_line() {
+        assert!(warnings("HLT\nX DAT // @allow unused-label").is_empty());
+        let source = "L LDA L // spare // @allow unused-label, self-modifying\nHLT";
+        assert!(warnings(source).is_empty());
+        assert_eq!(
+            warnings("HLT\nX DAT // @allow unreachable"),
+            ["unused-label"]
+        );
+        let source = "MACRO LOAD\nL LDA L\nENDM\n LOAD // @allow self-modifying\n HLT";
+        assert!(warnings(source).is_empty());
+    }
+}